@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use ezbrowsergameserver::prelude::*;
@@ -8,8 +8,15 @@ use ezbrowsergameserver::prelude::*;
 
 #[tokio::main]
 async fn main() {
-    // Host the game's WebSocket on 0.0.0.0:8081
-    host::<GlobalState>("0.0.0.0:8081").await;
+    // Host the game's WebSocket on 0.0.0.0:8081.
+    // Players who reload the page get 30 seconds to reconnect.
+    Server::<GlobalState>::builder()
+        .reconnect_grace(Some(Duration::from_secs(30)))
+        .bind("0.0.0.0:8081")
+        .await
+        .unwrap()
+        .run()
+        .await;
 }
 
 /// Since this is such a small demo, there is no global state.
@@ -23,19 +30,56 @@ struct GlobalState {
 impl LobbyState for GlobalState {
     // (name, ready)
     type PlayerState = (String, bool);
+    type SpectatorState = ();
+    // at least 2 players are needed to start a game, and at most 8 can join
+    const MIN_PLAYERS: usize = 2;
+    const MAX_PLAYERS: Option<usize> = Some(8);
     fn new() -> Self {
         Self { update: false }
     }
     fn new_player() -> Self::PlayerState {
         ("new player".to_owned(), false)
     }
-    async fn player_joined(_id: usize, lobby: &mut Lobby<Self>, _player: PlayerIndex) {
+    // the client sends its name when it connects, see `01_timer.html`
+    async fn accept_join(
+        _id: LobbyId,
+        _lobby: &mut Lobby<Self>,
+        request: &JoinRequest,
+    ) -> Result<(), JoinError> {
+        if request.name.trim().is_empty() {
+            Err(JoinError::Rejected("please enter a name".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+    async fn player_joined(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex) {
+        let player = lobby.get_player(player);
+        player.data.0 = player.join_request().name.clone();
+        // the client needs this to reconnect
+        let token = player.session_token().to_owned();
+        player.send(format!("t{token}")).await;
+        lobby.state.update = true;
+    }
+    async fn player_reconnected(_id: LobbyId, lobby: &mut Lobby<Self>, _player: PlayerId) {
         lobby.state.update = true;
     }
-    async fn player_leaving(_id: usize, lobby: &mut Lobby<Self>, _player: PlayerIndex) {
+    async fn player_leaving(_id: LobbyId, lobby: &mut Lobby<Self>, _player: PlayerId) {
+        lobby.state.update = true;
+    }
+    // called as soon as a message arrives, so we don't have to call `get_msg()` in `lobby_update()`
+    async fn on_message(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex, msg: String) {
+        let player = lobby.get_player(player);
+        if msg == "R1" {
+            player.data.1 = true;
+        } else if msg == "R0" {
+            player.data.1 = false;
+        }
         lobby.state.update = true;
     }
-    async fn lobby_update(id: usize, lobby: &mut Lobby<Self>) -> Option<Box<dyn GameState<Self>>> {
+    async fn lobby_update(
+        id: LobbyId,
+        lobby: &mut Lobby<Self>,
+    ) -> Option<Box<dyn GameState<Self>>> {
         let mut update = lobby.state.update;
         if lobby.reset {
             lobby.reset = false;
@@ -44,18 +88,6 @@ impl LobbyState for GlobalState {
                 player.data.1 = false;
             }
         }
-        for player in lobby.players_mut() {
-            while let Some(msg) = player.get_msg().await {
-                if msg.starts_with("n") {
-                    player.data.0 = msg[1..].to_owned();
-                } else if msg == "R1" {
-                    player.data.1 = true;
-                } else if msg == "R0" {
-                    player.data.1 = false;
-                }
-                update = true;
-            }
-        }
         if update {
             lobby.state.update = false;
             for player in lobby.player_indices() {
@@ -73,7 +105,7 @@ impl LobbyState for GlobalState {
                 let player = lobby.get_player(player);
                 player
                     .send(format!(
-                        "=<h1>Welcome to the lobby, {}!</h1><p>Lobby ID: {id:x}</p><p>{}</p><p>{players_list}</p>",
+                        "=<h1>Welcome to the lobby, {}!</h1><p>Lobby ID: {id}</p><p>{}</p><p>{players_list}</p>",
                         player.data.0,
                         if player.data.1 {
                             "<button onclick='con.send(\"R0\")'>Ready!</button>"
@@ -84,7 +116,7 @@ impl LobbyState for GlobalState {
                     .await;
             }
         }
-        if lobby.players().len() >= 2 && lobby.players().iter().all(|v| v.data.1) {
+        if lobby.has_enough_players() && lobby.players().iter().all(|v| v.data.1) {
             // all 2+ players are ready, start the game
             return Some(Box::new(TimerGame {
                 start: Instant::now(),
@@ -114,16 +146,18 @@ impl GameState<GlobalState> for TimerGame {
                 return true;
             }
             // update clients
-            for player in lobby.players_mut() {
-                player.send(format!("=<h1>T: {v}</h1>")).await;
-            }
+            lobby.broadcast(format!("=<h1>T: {v}</h1>")).await;
         }
         for player in lobby.players_mut() {
-            if let Some(_) = player.get_msg().await {
+            while player.get_msg().await.is_some() {
                 // ...
             }
         }
         false
     }
-    async fn player_leaving(&mut self, _lobby: &mut Lobby<GlobalState>, _player: PlayerIndex) {}
+    // players who join during the game have to wait until it's over
+    fn late_join(&self) -> LateJoin {
+        LateJoin::Queue
+    }
+    async fn player_leaving(&mut self, _lobby: &mut Lobby<GlobalState>, _player: PlayerId) {}
 }
@@ -1,11 +1,25 @@
 use std::time::Instant;
 
-use ezbrowsergameserver::prelude::*;
+use ezbrowsergameserver::{
+    prelude::*,
+    rating::{FileRatings, RankedMatchmaking},
+    site::Embedded,
+};
 use rand::{prelude::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
 
 #[tokio::main]
 async fn main() {
-    host::<GlobalState>("0.0.0.0:8081").await;
+    Server::<GlobalState>::builder()
+        .ratings(FileRatings::open("ratings.json").await.unwrap())
+        .ranked_matchmaking(RankedMatchmaking::default())
+        // open http://localhost:8081, or share http://localhost:8081/join/<code>
+        .serve(Embedded::new().file("index.html", include_bytes!("../../02_typing.html")))
+        .bind("0.0.0.0:8081")
+        .await
+        .unwrap()
+        .run()
+        .await;
 }
 
 struct GlobalState {
@@ -18,9 +32,47 @@ struct PS {
     text: String,
 }
 
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMsg {
+    Name { name: String },
+    Ready { ready: bool },
+    Text { text: String },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMsg {
+    /// show the lobby screen
+    Lobby {
+        id: String,
+    },
+    Players {
+        html: String,
+    },
+    Countdown {
+        secs: u64,
+    },
+    Start {
+        target: String,
+    },
+    Progress {
+        html: String,
+    },
+    Finished {
+        secs: f32,
+    },
+}
+
+impl TypedLobbyState for GlobalState {
+    type ClientMsg = ClientMsg;
+    type ServerMsg = ServerMsg;
+}
+
 #[async_trait]
 impl LobbyState for GlobalState {
     type PlayerState = PS;
+    type SpectatorState = ();
     fn new() -> Self {
         Self { update: true }
     }
@@ -32,13 +84,21 @@ impl LobbyState for GlobalState {
             text: String::new(),
         }
     }
-    async fn player_joined(_id: usize, lobby: &mut Lobby<Self>, _player: PlayerIndex) {
+    async fn player_joined(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex) {
+        let player = lobby.get_player(player);
+        // ranked players can't rename themselves, since their rating belongs to their name
+        if player.join_request().ranked {
+            player.data.name = player.join_request().name.clone();
+        }
         lobby.state.update = true;
     }
-    async fn player_leaving(_id: usize, lobby: &mut Lobby<Self>, _player: PlayerIndex) {
+    async fn player_leaving(_id: LobbyId, lobby: &mut Lobby<Self>, _player: PlayerId) {
         lobby.state.update = true;
     }
-    async fn lobby_update(id: usize, lobby: &mut Lobby<Self>) -> Option<Box<dyn GameState<Self>>> {
+    async fn lobby_update(
+        id: LobbyId,
+        lobby: &mut Lobby<Self>,
+    ) -> Option<Box<dyn GameState<Self>>> {
         let mut update = false;
         if lobby.state.update {
             update = true;
@@ -50,26 +110,22 @@ impl LobbyState for GlobalState {
             // show lobby screen to all clients
             for player in lobby.players_mut() {
                 player.data.ready = false;
-                player.send(format!("1{id:X}")).await;
+                player
+                    .send_msg::<Self>(&ServerMsg::Lobby { id: id.to_string() })
+                    .await;
             }
         }
         for player in lobby.players_mut() {
-            if let Some(msg) = player.get_msg().await {
-                match msg.chars().next() {
-                    Some('n') => {
-                        player.data.name = msg[1..].to_owned();
-                        update = true;
-                    }
-                    Some('R') => {
-                        player.data.ready = true;
-                        update = true;
-                    }
-                    Some('r') => {
-                        player.data.ready = false;
-                        update = true;
-                    }
-                    _ => (),
+            match player.recv_msg::<Self>().await {
+                Some(Ok(ClientMsg::Name { name })) if !player.join_request().ranked => {
+                    player.data.name = name;
+                    update = true;
+                }
+                Some(Ok(ClientMsg::Ready { ready })) => {
+                    player.data.ready = ready;
+                    update = true;
                 }
+                _ => (),
             }
         }
         if update {
@@ -91,7 +147,7 @@ impl LobbyState for GlobalState {
                     })
                     .collect::<String>();
                 let player = lobby.get_player(player);
-                player.send(format!("p{html}")).await;
+                player.send_msg::<Self>(&ServerMsg::Players { html }).await;
             }
             if lobby
                 .players()
@@ -137,9 +193,15 @@ impl GameState<GlobalState> for TypingGame {
                     if countdown == 0 {
                         player.data.text = String::new();
                         player.data.time = -1.0;
-                        player.send(format!("3{target}")).await;
+                        player
+                            .send_msg::<GlobalState>(&ServerMsg::Start {
+                                target: target.clone(),
+                            })
+                            .await;
                     } else {
-                        player.send(format!("2{countdown}...")).await;
+                        player
+                            .send_msg::<GlobalState>(&ServerMsg::Countdown { secs: countdown })
+                            .await;
                     }
                 }
                 if countdown == 0 {
@@ -153,15 +215,15 @@ impl GameState<GlobalState> for TypingGame {
         if self.state == 1 {
             // messages
             for player in lobby.players_mut() {
-                if let Some(msg) = player.get_msg().await {
-                    if msg.starts_with("=") {
-                        update_game_list = true;
-                        player.data.text = msg[1..].to_owned();
-                        if player.data.text == self.target {
-                            let secs = self.start.elapsed().as_secs_f32();
-                            player.data.time = secs;
-                            player.send(format!("X{:.2}", secs)).await;
-                        }
+                if let Some(Ok(ClientMsg::Text { text })) = player.recv_msg::<GlobalState>().await {
+                    update_game_list = true;
+                    player.data.text = text;
+                    if player.data.text == self.target {
+                        let secs = self.start.elapsed().as_secs_f32();
+                        player.data.time = secs;
+                        player
+                            .send_msg::<GlobalState>(&ServerMsg::Finished { secs })
+                            .await;
                     }
                 }
             }
@@ -204,24 +266,56 @@ impl GameState<GlobalState> for TypingGame {
                 })
                 .collect::<Vec<_>>();
             list.sort_by(|a, b| a.1.total_cmp(&b.1));
-            for player in lobby.players_mut() {
-                player
-                    .send(format!(
-                        "G{}",
-                        list.iter().map(|v| v.0.as_str()).collect::<String>()
-                    ))
-                    .await;
-            }
+            let html = list.iter().map(|v| v.0.as_str()).collect::<String>();
+            lobby.broadcast_msg(&ServerMsg::Progress { html }).await;
         }
         // game ends once all players finish
         if self.state == 1 && lobby.players().iter().all(|p| p.data.time >= 0.0) {
             self.start = Instant::now();
             self.state = 2;
+            // the fastest player wins
+            let result = lobby
+                .players()
+                .iter()
+                .map(|p| {
+                    let place = lobby
+                        .players()
+                        .iter()
+                        .filter(|o| o.data.time < p.data.time)
+                        .count() as u32;
+                    (p.data.name.as_str(), place)
+                })
+                .collect::<Vec<_>>();
+            // only ranked games are rated, where everyone kept the name they were matched with.
+            // Names aren't authenticated though, so anyone can still queue as "Mark"
+            let ranked = lobby.players().iter().all(|p| p.join_request().ranked);
+            let ratings = if ranked {
+                lobby.report_result(&result).await
+            } else {
+                None
+            };
+            let ratings = match ratings {
+                Some(ratings) => ratings.into_iter().map(Some).collect(),
+                None => vec![None; result.len()],
+            };
+            let html = result
+                .iter()
+                .zip(ratings)
+                .map(|((name, place), rating)| match rating {
+                    Some(rating) => format!(
+                        "<p>#{} {name} &emsp; <small>[{:.0}]</small></p>",
+                        place + 1,
+                        rating.value
+                    ),
+                    None => format!("<p>#{} {name}</p>", place + 1),
+                })
+                .collect::<String>();
+            lobby.broadcast_msg(&ServerMsg::Progress { html }).await;
         }
         // exit after 3 seconds
         self.state == 2 && self.start.elapsed().as_secs() >= 3
     }
-    async fn player_leaving(&mut self, _lobby: &mut Lobby<GlobalState>, _player: PlayerIndex) {}
+    async fn player_leaving(&mut self, _lobby: &mut Lobby<GlobalState>, _player: PlayerId) {}
 }
 
 fn get_phrase() -> String {
@@ -1,11 +1,15 @@
 use std::time::{Duration, Instant};
 
-use ezbrowsergameserver::prelude::*;
+use ezbrowsergameserver::{prelude::*, Connector};
 use rand::seq::SliceRandom;
-use tokio::net::ToSocketAddrs;
 
-pub async fn main(addr: impl ToSocketAddrs + Send + 'static) {
-    host::<LobbyS>(addr).await;
+/// Starts the game server. It doesn't listen on its own port,
+/// the site passes the WebSockets to it instead.
+pub async fn start() -> Connector {
+    let server = Server::<LobbyS>::builder().build().await.unwrap();
+    let connector = server.connector();
+    tokio::spawn(server.run());
+    connector
 }
 
 struct LobbyS {
@@ -25,9 +29,7 @@ impl LobbyS {
                 }
             }))
             .collect::<String>();
-        for player in lobby.players_mut() {
-            player.send(msg.clone()).await;
-        }
+        lobby.broadcast(msg).await;
     }
 }
 
@@ -39,12 +41,13 @@ struct PlayerS {
     points_total: isize,
     /// i8::MIN -> no vote yet
     received_vote: (i8, i8),
-    leftright: Option<(PlayerIndex, PlayerIndex)>,
+    leftright: Option<(PlayerId, PlayerId)>,
 }
 
 #[async_trait]
 impl LobbyState for LobbyS {
     type PlayerState = PlayerS;
+    type SpectatorState = ();
     fn new() -> Self {
         Self {
             ready_since: None,
@@ -62,17 +65,17 @@ impl LobbyState for LobbyS {
             leftright: None,
         }
     }
-    async fn player_joined(id: usize, lobby: &mut Lobby<Self>, player: PlayerIndex) {
+    async fn player_joined(id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex) {
         Self::update_players_list(lobby).await;
         let mp = lobby.state.max_points;
         let p = lobby.get_player(player);
         p.send(format!("0{id}")).await;
         p.send(format!("sMP{mp}")).await;
     }
-    async fn player_leaving(_id: usize, lobby: &mut Lobby<Self>, _player: PlayerIndex) {
+    async fn player_leaving(_id: LobbyId, lobby: &mut Lobby<Self>, _player: PlayerId) {
         Self::update_players_list(lobby).await;
     }
-    async fn lobby_update(id: usize, lobby: &mut Lobby<Self>) -> Option<Box<dyn GameState<Self>>> {
+    async fn lobby_update(id: LobbyId, lobby: &mut Lobby<Self>) -> Option<Box<dyn GameState<Self>>> {
         let mut update_list = false;
         if lobby.reset {
             lobby.reset = false;
@@ -103,11 +106,8 @@ impl LobbyState for LobbyS {
                         "MP" => {
                             if let Ok(v) = msg[3..].parse() {
                                 lobby.state.max_points = v;
-                                for (i, player) in lobby.players_mut().enumerate() {
-                                    if i != player_index.i() {
-                                        player.send(format!("sMP{v}")).await;
-                                    }
-                                }
+                                let id = lobby.get_player(player_index).id();
+                                lobby.broadcast_except(id, format!("sMP{v}")).await;
                             }
                         }
                         _ => {}
@@ -130,9 +130,7 @@ impl LobbyState for LobbyS {
             }
         } else {
             if lobby.state.ready_since.is_some() {
-                for player in lobby.players_mut() {
-                    player.send(format!("0{id}")).await;
-                }
+                lobby.broadcast(format!("0{id}")).await;
                 lobby.state.ready_since = None;
             }
         }
@@ -144,12 +142,10 @@ impl LobbyState for LobbyS {
                     return Some(Box::new(GameS {
                         force_exit: false,
                         round: 0,
-                        players: lobby.player_indices().collect(),
+                        players: lobby.player_ids().collect(),
                     }));
                 } else {
-                    for player in lobby.players_mut() {
-                        player.send(format!("1{}", 3 - secs)).await;
-                    }
+                    lobby.broadcast(format!("1{}", 3 - secs)).await;
                 }
             }
         }
@@ -160,7 +156,7 @@ impl LobbyState for LobbyS {
 struct GameS {
     force_exit: bool,
     round: usize,
-    players: Vec<PlayerIndex>,
+    players: Vec<PlayerId>,
 }
 
 #[async_trait]
@@ -205,9 +201,7 @@ impl GameState<LobbyS> for GameS {
                     }))
                     .chain(best2)
                     .collect::<String>();
-                for player in lobby.players_mut() {
-                    player.send(msg.clone()).await;
-                }
+                lobby.broadcast(msg).await;
                 tokio::time::sleep(Duration::from_secs(3)).await;
                 if self.force_exit
                     || lobby
@@ -238,7 +232,9 @@ impl GameState<LobbyS> for GameS {
                 } else {
                     self.players[i + 1]
                 };
-                lobby.get_player(*player).data.leftright = Some((l, r));
+                if let Some(player) = lobby.player(*player) {
+                    player.data.leftright = Some((l, r));
+                }
             }
         }
         // messages
@@ -249,20 +245,28 @@ impl GameState<LobbyS> for GameS {
                     Some('L') => {
                         if let Ok(vote) = msg[1..].parse() {
                             let p = player.data.leftright.unwrap().0;
-                            lobby.get_player(p).data.received_vote.1 = vote;
+                            if let Some(p) = lobby.player(p) {
+                                p.data.received_vote.1 = vote;
+                            }
                         }
                     }
                     Some('R') => {
                         if let Ok(vote) = msg[1..].parse() {
                             let p = player.data.leftright.unwrap().1;
-                            lobby.get_player(p).data.received_vote.0 = vote;
+                            if let Some(p) = lobby.player(p) {
+                                p.data.received_vote.0 = vote;
+                            }
                         }
                     }
                     Some('=') => {
                         let text = msg[1..].to_owned();
                         let lr = player.data.leftright.unwrap();
-                        lobby.get_player(lr.0).send(format!("R{text}")).await;
-                        lobby.get_player(lr.1).send(format!("L{text}")).await;
+                        if let Some(l) = lobby.player(lr.0) {
+                            l.send(format!("R{text}")).await;
+                        }
+                        if let Some(r) = lobby.player(lr.1) {
+                            r.send(format!("L{text}")).await;
+                        }
                         lobby.get_player(player_index).data.text = text;
                     }
                     _ => {}
@@ -271,7 +275,7 @@ impl GameState<LobbyS> for GameS {
         }
         false
     }
-    async fn player_leaving(&mut self, _lobby: &mut Lobby<LobbyS>, _player: PlayerIndex) {
+    async fn player_leaving(&mut self, _lobby: &mut Lobby<LobbyS>, _player: PlayerId) {
         self.force_exit = true;
     }
 }
@@ -7,8 +7,7 @@ mod site;
 
 #[derive(Parser)]
 struct Args {
-    site_addr: SocketAddr,
-    socket_addr: SocketAddr,
+    addr: SocketAddr,
 }
 
 // Server -> Client
@@ -32,7 +31,7 @@ struct Args {
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    _ = tokio::spawn(ezgame::main(args.socket_addr.clone()));
-    let site = site::Site::new(args.site_addr, args.socket_addr);
-    site.main().await;
+    let connector = ezgame::start().await;
+    let site = site::Site::new(args.addr);
+    site.main(connector).await;
 }
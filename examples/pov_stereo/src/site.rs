@@ -6,6 +6,7 @@ use axum::{
     routing::get,
     Router,
 };
+use ezbrowsergameserver::Connector;
 
 type S = Arc<Site>;
 pub struct Site {
@@ -13,19 +14,20 @@ pub struct Site {
     html: String,
 }
 impl Site {
-    pub fn new(site_addr: SocketAddr, socket_addr: SocketAddr) -> Self {
-        let port = socket_addr.port();
+    pub fn new(site_addr: SocketAddr) -> Self {
         Self {
             site_addr,
-            html: include_str!("site.html").replace("\\{port}", port.to_string().as_str()),
+            html: include_str!("site.html").to_owned(),
         }
     }
-    pub async fn main(self) -> ! {
+    /// serves the site, and the game's WebSocket on `/ws`
+    pub async fn main(self, connector: Connector) -> ! {
         let addr = self.site_addr.clone();
         let r = Router::new()
             .route("/", get(Self::root))
             .route("/join/:id", get(Self::join))
-            .with_state(Arc::new(self));
+            .with_state(Arc::new(self))
+            .merge(ezbrowsergameserver::axum::router(connector));
         axum::Server::bind(&addr)
             .serve(r.into_make_service())
             .await
@@ -0,0 +1,63 @@
+//! Embedding the server in an axum app, so that the game and the rest of the site
+//! share one port (and one TLS setup).
+//!
+//! Create the server with `ServerBuilder::build`, so that it doesn't listen on its own port,
+//! and add `router(server.connector())` to your app. Clients then connect to `ws://your.site/ws`.
+//! The router also serves the JavaScript client (see `js`) at `/ezbrowsergameserver.js`,
+//! which connects to `/ws` by default.
+
+use ::axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+use crate::{http::websocket_accept_key, js, server::Connector, transport::Transport};
+
+/// A `Router` which upgrades requests to `/ws` to WebSockets and hands them to the server,
+/// and serves the JavaScript client. Merge it into your app using `Router::merge`.
+pub fn router<S: Clone + Send + Sync + 'static>(connector: Connector) -> Router<S> {
+    Router::new()
+        .route(
+            "/ws",
+            get(move |req: Request<Body>| upgrade(connector.clone(), req)),
+        )
+        .route(js::PATH, get(client))
+}
+
+async fn client() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        format!("{}EzClient.defaultPath = \"/ws\";\n", js::CLIENT),
+    )
+}
+
+/// Upgrades `req` to a WebSocket and hands it to the server, like `router` does for `/ws`.
+/// Use this if the WebSocket should have a different path.
+///
+/// Responds with `400 Bad Request` if `req` isn't a WebSocket handshake.
+pub async fn upgrade(connector: Connector, req: Request<Body>) -> Response {
+    let Some(accept) = websocket_accept_key(req.method(), req.headers()) else {
+        return (StatusCode::BAD_REQUEST, "expected a WebSocket handshake").into_response();
+    };
+    // hyper only hands out the connection once the response has been sent
+    tokio::spawn(async move {
+        if let Ok(upgraded) = hyper::upgrade::on(req).await {
+            let transport: Box<dyn Transport> = Box::new(upgraded);
+            let con = WebSocketStream::from_raw_socket(transport, Role::Server, None).await;
+            connector.connect_websocket(con);
+        }
+    });
+    (
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::CONNECTION, "upgrade".to_owned()),
+            (header::UPGRADE, "websocket".to_owned()),
+            (header::SEC_WEBSOCKET_ACCEPT, accept),
+        ],
+    )
+        .into_response()
+}
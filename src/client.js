@@ -0,0 +1,394 @@
+// The JavaScript client for ezbrowsergameserver, see the `js` module of the crate.
+//
+// The crate serves this at `/ezbrowsergameserver.js`, wrapped in a function
+// which receives the crate's version as `VERSION` and defines the global `EzClient`.
+
+"use strict";
+
+// see `handshake::PROTOCOL_VERSION`
+const PROTOCOL_VERSION = 1;
+
+// where this script was loaded from, which is usually the game server
+const scriptUrl =
+  typeof document !== "undefined" && document.currentScript ? document.currentScript.src : "";
+
+const now = () => (typeof performance !== "undefined" ? performance.now() : Date.now());
+
+/** Why a client couldn't join a lobby, see `JoinError` in the crate. */
+class EzJoinError extends Error {
+  constructor(code, reason) {
+    super(reason || "connection closed (" + code + ")");
+    this.name = "EzJoinError";
+    this.code = code;
+    this.reason = reason;
+  }
+}
+EzJoinError.BAD_HANDSHAKE = 4000;
+EzJoinError.LOBBY_NOT_FOUND = 4001;
+EzJoinError.SESSION_NOT_FOUND = 4002;
+EzJoinError.LOBBY_FULL = 4003;
+EzJoinError.GAME_IN_PROGRESS = 4004;
+EzJoinError.TOO_MANY_LOBBIES = 4005;
+EzJoinError.REJECTED = 4010;
+
+/**
+ * A connection to a game server.
+ *
+ *     const client = new EzClient();
+ *     client.onmessage = (msg) => console.log(msg);
+ *     await client.join("KXQF", { name: "Mark" });
+ *     client.send({ type: "ready" });
+ *
+ * If the connection is lost and the server allows reconnecting (`ServerBuilder::reconnect_grace`),
+ * the client reconnects using its session token. Messages sent while it is disconnected are dropped.
+ */
+class EzClient {
+  /**
+   * `url` is the server's WebSocket URL. By default, that is the server this script was loaded from.
+   *
+   * Options:
+   * - `reconnect`: reconnect after losing the connection (default `true`)
+   * - `reconnectTimeout`: give up reconnecting after this many milliseconds (default 30000)
+   * - `pingInterval`: measure the latency this often, in milliseconds, or never if 0 (default 5000)
+   * - `requestTimeout`: the default timeout of `request()`, in milliseconds (default 10000)
+   */
+  constructor(url, options) {
+    this.url = url || EzClient.defaultUrl();
+    this.options = Object.assign(
+      { reconnect: true, reconnectTimeout: 30000, pingInterval: 5000, requestTimeout: 10000 },
+      options
+    );
+    /** The code of the lobby this client is in, or `null`. */
+    this.lobby = null;
+    /** The session token used for reconnecting, or `null` if the server doesn't allow it. */
+    this.token = null;
+    /** True if this client joined as a spectator. */
+    this.spectator = false;
+    /** The last measured round-trip time to the server in milliseconds, or `null`. */
+    this.latency = null;
+    /** True while the client is in a lobby and connected. */
+    this.connected = false;
+
+    /** Called with `{ lobby, token, spectator, resumed }` when the client joined a lobby or reconnected. */
+    this.onjoin = null;
+    /**
+     * Called for every message from the game: a parsed object or array for JSON ones,
+     * the string for other text messages, and an `ArrayBuffer` for binary messages.
+     */
+    this.onmessage = null;
+    /** Called with `{ attempt, code, reason }` before every attempt to reconnect. */
+    this.onreconnecting = null;
+    /** Called with `{ code, reason }` once the connection is closed for good. */
+    this.onclose = null;
+    /** Called with the latency in milliseconds whenever it was measured. */
+    this.onlatency = null;
+
+    this._ws = null;
+    this._closed = false;
+    this._joining = null;
+    this._reconnectingSince = null;
+    this._attempt = 0;
+    this._reconnectTimer = null;
+    this._pingTimer = null;
+    this._requests = new Map();
+    this._nextRequest = 1;
+  }
+
+  /**
+   * Creates a new lobby and joins it. Resolves with the same object `onjoin` receives,
+   * or rejects with an `EzJoinError`.
+   *
+   * Options (all optional): `name`, `password`, `clientVersion`, `meta` (any JSON value),
+   * `spectate` (join as a spectator), and `mode` (the game mode, used by quickplay).
+   */
+  create(options) {
+    return this._connect(handshake({}, options));
+  }
+  /** Joins the lobby with this code, see `create()`. */
+  join(code, options) {
+    return this._connect(handshake({ lobby: code }, options));
+  }
+  /** Joins the best fitting public lobby, or creates one if none fits, see `create()`. */
+  quickplay(options) {
+    return this._connect(handshake({ quickplay: true }, options));
+  }
+  /** Waits for players with a similar rating, then joins a new lobby with them. Needs a `name`. */
+  ranked(options) {
+    return this._connect(handshake({ ranked: true }, options));
+  }
+  /** Takes over the session with this token, for example after the page was reloaded. */
+  resume(token) {
+    return this._connect({ v: PROTOCOL_VERSION, resume: token, welcome: true });
+  }
+
+  /**
+   * Sends a message to the game: strings as they are, `ArrayBuffer`s and typed arrays as binary messages,
+   * and everything else as JSON. Returns false if the client isn't connected.
+   */
+  send(msg) {
+    if (!this.connected || this._ws.readyState !== 1) {
+      return false;
+    }
+    if (typeof msg === "string" || msg instanceof ArrayBuffer || ArrayBuffer.isView(msg)) {
+      this._ws.send(msg);
+    } else {
+      this._ws.send(JSON.stringify(msg));
+    }
+    return true;
+  }
+
+  /**
+   * Sends `msg` (an object) with a `rid` field, and resolves with the game's answer,
+   * which it sends using `player.reply()` or `player.reply_msg()`.
+   * Rejects if there is no answer within `timeout` milliseconds or the connection is lost.
+   */
+  request(msg, timeout) {
+    return new Promise((resolve, reject) => {
+      const rid = this._nextRequest++;
+      if (!this.send(Object.assign({}, msg, { rid }))) {
+        reject(new Error("not connected"));
+        return;
+      }
+      const timer = setTimeout(() => {
+        this._requests.delete(rid);
+        reject(new Error("request timed out"));
+      }, timeout === undefined ? this.options.requestTimeout : timeout);
+      this._requests.set(rid, { resolve, reject, timer });
+    });
+  }
+
+  /** Leaves the lobby and closes the connection without reconnecting. */
+  close() {
+    this._closed = true;
+    if (this._ws) {
+      this._ws.close(1000);
+    } else if (this._reconnectTimer !== null) {
+      clearTimeout(this._reconnectTimer);
+      this._reconnectTimer = null;
+      this._finish(1000, "");
+    }
+  }
+
+  _connect(msg) {
+    if (this._ws || this._reconnectTimer !== null) {
+      return Promise.reject(new Error("already connected"));
+    }
+    this._closed = false;
+    return new Promise((resolve, reject) => {
+      this._joining = { resolve, reject };
+      this._open(msg);
+    });
+  }
+
+  _open(msg) {
+    const ws = new WebSocket(this.url);
+    ws.binaryType = "arraybuffer";
+    ws.onopen = () => ws.send(JSON.stringify(msg));
+    ws.onmessage = (e) => this._receive(e.data);
+    ws.onclose = (e) => this._disconnected(ws, e.code, e.reason);
+    this._ws = ws;
+  }
+
+  _receive(data) {
+    let msg = data;
+    if (typeof data === "string" && /^\s*[[{]/.test(data)) {
+      try {
+        msg = JSON.parse(data);
+      } catch (_) {
+        // not JSON after all
+      }
+    }
+    if (msg !== null && typeof msg === "object" && !Array.isArray(msg) && !(msg instanceof ArrayBuffer)) {
+      // the `ez` field is reserved for messages from the crate itself
+      if (msg.ez === "welcome") {
+        this._welcome(msg);
+        return;
+      }
+      if (msg.ez === "pong") {
+        this.latency = now() - msg.t;
+        emit(this.onlatency, this.latency);
+        return;
+      }
+      const request = typeof msg.re === "number" && this._requests.get(msg.re);
+      if (request) {
+        this._requests.delete(msg.re);
+        clearTimeout(request.timer);
+        request.resolve(msg);
+        return;
+      }
+    }
+    emit(this.onmessage, msg);
+  }
+
+  _welcome(msg) {
+    this.lobby = msg.lobby;
+    this.token = msg.token;
+    this.spectator = msg.spectator;
+    this.connected = true;
+    this._reconnectingSince = null;
+    this._attempt = 0;
+    this._startPing();
+    const info = { lobby: msg.lobby, token: msg.token, spectator: msg.spectator, resumed: msg.resumed };
+    if (this._joining) {
+      this._joining.resolve(info);
+      this._joining = null;
+    }
+    emit(this.onjoin, info);
+  }
+
+  _disconnected(ws, code, reason) {
+    if (ws !== this._ws) {
+      return;
+    }
+    this._ws = null;
+    this.connected = false;
+    this._stopPing();
+    for (const request of this._requests.values()) {
+      clearTimeout(request.timer);
+      request.reject(new Error("disconnected"));
+    }
+    this._requests.clear();
+    // codes 4000 to 4999 say why the server doesn't want us (see `JoinError`), 1001 means it is shutting down.
+    // 4002 is final, too: the server accepts our token for as long as the player is in the lobby,
+    // even before it notices that our old connection is gone. 4006 means another connection took over.
+    const retry =
+      !this._closed &&
+      this.options.reconnect &&
+      this.token &&
+      !(code >= 4000 && code <= 4999) &&
+      code !== 1001;
+    if (retry) {
+      if (this._reconnectingSince === null) {
+        this._reconnectingSince = Date.now();
+      }
+      if (Date.now() - this._reconnectingSince < this.options.reconnectTimeout) {
+        this._attempt++;
+        emit(this.onreconnecting, { attempt: this._attempt, code, reason });
+        const delay = Math.min(250 * 2 ** this._attempt, 5000);
+        this._reconnectTimer = setTimeout(() => {
+          this._reconnectTimer = null;
+          this._open({ v: PROTOCOL_VERSION, resume: this.token, welcome: true });
+        }, delay);
+        return;
+      }
+    }
+    this._finish(code, reason);
+  }
+
+  _finish(code, reason) {
+    this.lobby = null;
+    this.token = null;
+    this._reconnectingSince = null;
+    this._attempt = 0;
+    if (this._joining) {
+      this._joining.reject(new EzJoinError(code, reason));
+      this._joining = null;
+    }
+    emit(this.onclose, { code, reason });
+  }
+
+  _startPing() {
+    this._stopPing();
+    if (!this.options.pingInterval) {
+      return;
+    }
+    // the server answers these itself, see `CLIENT_PING` in the crate
+    const ping = () => {
+      if (this._ws && this._ws.readyState === 1) {
+        this._ws.send(JSON.stringify({ ez: "ping", t: now() }));
+      }
+    };
+    ping();
+    this._pingTimer = setInterval(ping, this.options.pingInterval);
+  }
+
+  _stopPing() {
+    if (this._pingTimer !== null) {
+      clearInterval(this._pingTimer);
+      this._pingTimer = null;
+    }
+  }
+
+  /**
+   * The WebSocket URL of the server this script was loaded from, or of the page's own host,
+   * with the path `EzClient.defaultPath`.
+   */
+  static defaultUrl() {
+    const base = scriptUrl || (typeof location !== "undefined" ? location.href : "http://localhost/");
+    const url = new URL(base);
+    return (url.protocol === "https:" ? "wss://" : "ws://") + url.host + EzClient.defaultPath;
+  }
+
+  /**
+   * The lobby code in a URL (by default the page's), from a `/join/<code>` path,
+   * a `?lobby=<code>` parameter or a `#<code>` fragment. `null` if there is none.
+   */
+  static lobbyFromUrl(url) {
+    url = new URL(url || location.href);
+    const path = /\/join\/([^/]+)\/?$/.exec(url.pathname);
+    if (path) {
+      return decodeURIComponent(path[1]);
+    }
+    return url.searchParams.get("lobby") || url.hash.slice(1) || null;
+  }
+
+  /** A link which opens the page served by the game server (see `ServerBuilder::serve`) and joins this lobby. */
+  static joinLink(code, base) {
+    const url = new URL(base || scriptUrl || location.href);
+    return url.origin + "/join/" + encodeURIComponent(code);
+  }
+
+  /** Resolves with the list of public lobbies, see `LobbyState::listing`. */
+  static listLobbies(url) {
+    return new Promise((resolve, reject) => {
+      const ws = new WebSocket(url || EzClient.defaultUrl());
+      let done = false;
+      ws.onopen = () => ws.send(JSON.stringify({ v: PROTOCOL_VERSION, list: true }));
+      ws.onmessage = (e) => {
+        done = true;
+        resolve(JSON.parse(e.data).lobbies);
+        ws.close();
+      };
+      ws.onclose = (e) => {
+        if (!done) {
+          reject(new EzJoinError(e.code, e.reason));
+        }
+      };
+    });
+  }
+}
+EzClient.VERSION = VERSION;
+EzClient.PROTOCOL_VERSION = PROTOCOL_VERSION;
+// the path of the WebSocket, which the `axum` module's router sets to `/ws`
+EzClient.defaultPath = "/";
+EzClient.JoinError = EzJoinError;
+
+function handshake(fields, options) {
+  const msg = { v: PROTOCOL_VERSION, welcome: true };
+  options = options || {};
+  const names = {
+    name: "name",
+    password: "password",
+    clientVersion: "client_version",
+    meta: "meta",
+    spectate: "spectate",
+    mode: "mode",
+  };
+  for (const key in names) {
+    if (options[key] !== undefined) {
+      msg[names[key]] = options[key];
+    }
+  }
+  return Object.assign(msg, fields);
+}
+
+function emit(handler, value) {
+  if (typeof handler === "function") {
+    handler(value);
+  }
+}
+
+globalThis.EzClient = EzClient;
+if (typeof module === "object" && module.exports) {
+  module.exports = EzClient;
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        Notify,
+    },
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_tungstenite::{
+    tungstenite::{protocol::CloseFrame, Message},
+    WebSocketStream,
+};
+
+use crate::{
+    server::{Config, Overflow},
+    transport::Transport,
+};
+
+pub(crate) type WebSocket = WebSocketStream<Box<dyn Transport>>;
+type WsSink = SplitSink<WebSocket, Message>;
+
+/// How long `close` waits for the writer task to send what is left in the queue.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Text messages starting with this are latency measurements from the JavaScript client (see `js`).
+/// They are sent back with `"ping"` replaced by `"pong"`, and never reach the game.
+const CLIENT_PING: &str = "{\"ez\":\"ping\"";
+
+/// A player's WebSocket.
+///
+/// Incoming messages are read by a background task, which also sends pings
+/// and marks the connection as closed if the client stops responding.
+/// Outgoing messages are put into a queue, which another background task sends,
+/// so sending never waits for a slow client.
+pub(crate) struct Connection {
+    shared: Arc<ConShared>,
+    /// messages from the reader task
+    incoming: mpsc::UnboundedReceiver<Message>,
+    reader: JoinHandle<()>,
+    /// `None` once `close` has waited for it
+    writer: Option<JoinHandle<()>>,
+}
+
+/// the parts of the connection which are used by `Connection` and its tasks
+struct ConShared {
+    /// messages waiting to be sent by the writer task
+    queue: std::sync::Mutex<VecDeque<Queued>>,
+    /// notified when something is added to `queue`, or the connection is closed
+    queue_changed: Notify,
+    queue_capacity: usize,
+    overflow: Overflow,
+    closed: AtomicBool,
+    /// notified when a message arrives or the connection is closed
+    events: std::sync::Mutex<Arc<Notify>>,
+}
+
+enum Queued {
+    /// a message, and its key if it was sent with `send_keyed`
+    Msg(Outgoing, Option<String>),
+    /// sends the close frame (if any), then stops the writer task
+    Close(Option<CloseFrame<'static>>),
+}
+
+/// A message waiting in a connection's queue.
+///
+/// tungstenite needs an owned `String` or `Vec<u8>` for every message it sends,
+/// so a broadcast can't share one buffer all the way to the socket.
+/// Instead, the queues share one `Arc`, and the writer task only copies it into a `Message`
+/// right before sending it. That way, a broadcast to many slow connections is only kept in memory once.
+pub(crate) enum Outgoing {
+    Msg(Message),
+    SharedText(Arc<str>),
+    SharedBinary(Arc<[u8]>),
+}
+
+impl Outgoing {
+    /// moves `msg` into a buffer which can be queued on many connections
+    pub(crate) fn shared(msg: Message) -> Self {
+        match msg {
+            Message::Text(text) => Self::SharedText(text.into()),
+            Message::Binary(bytes) => Self::SharedBinary(bytes.into()),
+            msg => Self::Msg(msg),
+        }
+    }
+    /// a copy which shares the buffer
+    pub(crate) fn share(&self) -> Self {
+        match self {
+            Self::Msg(msg) => Self::Msg(msg.clone()),
+            Self::SharedText(text) => Self::SharedText(Arc::clone(text)),
+            Self::SharedBinary(bytes) => Self::SharedBinary(Arc::clone(bytes)),
+        }
+    }
+    fn into_message(self) -> Message {
+        match self {
+            Self::Msg(msg) => msg,
+            Self::SharedText(text) => Message::Text(text.as_ref().to_owned()),
+            Self::SharedBinary(bytes) => Message::Binary(bytes.to_vec()),
+        }
+    }
+}
+
+impl From<Message> for Outgoing {
+    fn from(msg: Message) -> Self {
+        Self::Msg(msg)
+    }
+}
+
+impl Connection {
+    /// Starts a task which reads messages from `con` and notifies `events` when one arrives
+    /// or when the connection is closed, and one which sends queued messages.
+    pub(crate) fn new(con: WebSocket, events: Arc<Notify>, config: &Config) -> Self {
+        let (sink, stream) = con.split();
+        let shared = Arc::new(ConShared {
+            queue: Default::default(),
+            queue_changed: Notify::new(),
+            queue_capacity: config.outbound_queue,
+            overflow: config.overflow,
+            closed: AtomicBool::new(false),
+            events: std::sync::Mutex::new(events),
+        });
+        let (sender, incoming) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read(
+            stream,
+            sender,
+            Arc::clone(&shared),
+            config.ping_interval,
+            config.ping_timeout,
+        ));
+        let writer = tokio::spawn(write(sink, Arc::clone(&shared), reader.abort_handle()));
+        Self {
+            shared,
+            incoming,
+            reader,
+            writer: Some(writer),
+        }
+    }
+    /// Notifies `events` instead of the previous `Notify` from now on,
+    /// for example when a player waiting for ranked matchmaking moves into a lobby.
+    pub(crate) fn set_events(&self, events: Arc<Notify>) {
+        *self.shared.events.lock().unwrap() = events;
+    }
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+    /// queues a message. If the queue is full, `Overflow` decides what happens.
+    pub(crate) fn send(&self, msg: impl Into<Outgoing>) {
+        self.shared.send(msg.into(), None);
+    }
+    /// like `send`, but with `Overflow::Coalesce`, this can replace a queued message with the same key.
+    pub(crate) fn send_keyed(&self, key: String, msg: Message) {
+        self.shared.send(msg.into(), Some(key));
+    }
+    /// how many messages are waiting to be sent
+    pub(crate) fn queue_len(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+    /// sends the queued messages and `frame` (if any), then closes the connection.
+    /// If the client doesn't take the messages fast enough, the connection is closed without sending all of them.
+    pub(crate) async fn close(&mut self, frame: Option<CloseFrame<'static>>) {
+        self.reader.abort();
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+        if !self.shared.closed.swap(true, Ordering::Relaxed) {
+            self.shared
+                .queue
+                .lock()
+                .unwrap()
+                .push_back(Queued::Close(frame));
+        }
+        self.shared.queue_changed.notify_one();
+        if tokio::time::timeout(CLOSE_TIMEOUT, &mut writer)
+            .await
+            .is_err()
+        {
+            writer.abort();
+        }
+    }
+    /// the next message from the reader task, if there is one.
+    /// Returns `None` once the connection is closed and all messages have been received.
+    pub(crate) fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        self.incoming.try_recv()
+    }
+    /// waits for the next message from the reader task.
+    /// Returns `None` once the connection is closed and all messages have been received.
+    pub(crate) async fn recv(&mut self) -> Option<Message> {
+        self.incoming.recv().await
+    }
+}
+
+impl ConShared {
+    fn notify(&self) {
+        self.events.lock().unwrap().notify_one();
+    }
+    fn send(&self, msg: Outgoing, key: Option<String>) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.queue_capacity {
+            let make_room = match self.overflow {
+                Overflow::DropOldest => Some(0),
+                Overflow::Coalesce => key.as_ref().and_then(|key| {
+                    queue
+                        .iter()
+                        .position(|q| matches!(q, Queued::Msg(_, Some(k)) if k == key))
+                }),
+                Overflow::Disconnect => None,
+            };
+            match make_room {
+                Some(index) => {
+                    queue.remove(index);
+                }
+                None => {
+                    // the client is too slow. The writer task stops once it sees that the queue is empty.
+                    queue.clear();
+                    self.closed.store(true, Ordering::Relaxed);
+                    drop(queue);
+                    self.queue_changed.notify_one();
+                    self.notify();
+                    return;
+                }
+            }
+        }
+        queue.push_back(Queued::Msg(msg, key));
+        drop(queue);
+        self.queue_changed.notify_one();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        if let Some(writer) = &self.writer {
+            writer.abort();
+        }
+    }
+}
+
+/// Sends queued messages until the connection is closed.
+/// Afterwards, stops the reader task, so that the socket is closed.
+async fn write(mut sink: WsSink, shared: Arc<ConShared>, reader: AbortHandle) {
+    loop {
+        let next = shared.queue.lock().unwrap().pop_front();
+        match next {
+            Some(Queued::Msg(msg, _)) => {
+                // this fails once the client has sent a close frame
+                if sink.send(msg.into_message()).await.is_err() {
+                    break;
+                }
+            }
+            Some(Queued::Close(frame)) => {
+                if let Some(frame) = frame {
+                    _ = sink.send(Message::Close(Some(frame))).await;
+                }
+                break;
+            }
+            None if shared.closed.load(Ordering::Relaxed) => break,
+            None => shared.queue_changed.notified().await,
+        }
+    }
+    // sends our close frame, or answers the client's
+    _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.close()).await;
+    shared.closed.store(true, Ordering::Relaxed);
+    reader.abort();
+    shared.notify();
+}
+
+async fn read(
+    mut stream: futures_util::stream::SplitStream<WebSocket>,
+    sender: mpsc::UnboundedSender<Message>,
+    shared: Arc<ConShared>,
+    ping_interval: Option<Duration>,
+    ping_timeout: Option<Duration>,
+) {
+    let mut ping = ping_interval.map(crate::server::interval);
+    let mut last_seen = Instant::now();
+    loop {
+        let timeout = ping_timeout.map(|timeout| last_seen + timeout);
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                last_seen = Instant::now();
+                match msg {
+                    // pings are answered by tungstenite
+                    Message::Ping(_) | Message::Pong(_) => {}
+                    Message::Text(text) if text.starts_with(CLIENT_PING) => {
+                        let pong = text.replacen("\"ping\"", "\"pong\"", 1);
+                        shared.send(Message::Text(pong).into(), None);
+                    }
+                    msg => {
+                        let close = matches!(msg, Message::Close(_));
+                        if sender.send(msg).is_err() {
+                            break;
+                        }
+                        shared.notify();
+                        if close {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = async { ping.as_mut().unwrap().tick().await }, if ping.is_some() => {
+                shared.send(Message::Ping(vec![]).into(), None);
+                if shared.closed.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(timeout.unwrap_or_else(Instant::now).into()), if timeout.is_some() => {
+                // the client hasn't sent anything, not even a pong, for too long
+                break;
+            }
+        }
+    }
+    // the connection is gone. dropping `sender` lets `PlayerCon` know.
+    shared.closed.store(true, Ordering::Relaxed);
+    drop(sender);
+    shared.queue_changed.notify_one();
+    shared.notify();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a connection without tasks, whose queue holds 2 messages
+    fn shared(overflow: Overflow) -> ConShared {
+        ConShared {
+            queue: Default::default(),
+            queue_changed: Notify::new(),
+            queue_capacity: 2,
+            overflow,
+            closed: AtomicBool::new(false),
+            events: Default::default(),
+        }
+    }
+
+    fn send(shared: &ConShared, text: &str, key: Option<&str>) {
+        shared.send(
+            Message::Text(text.to_owned()).into(),
+            key.map(str::to_owned),
+        );
+    }
+
+    /// the texts of the queued messages
+    fn queued(shared: &ConShared) -> Vec<String> {
+        let queue = shared.queue.lock().unwrap();
+        queue
+            .iter()
+            .map(|q| match q {
+                Queued::Msg(Outgoing::Msg(Message::Text(text)), _) => text.clone(),
+                _ => panic!("expected a text message"),
+            })
+            .collect()
+    }
+
+    fn closed(shared: &ConShared) -> bool {
+        shared.closed.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let shared = shared(Overflow::DropOldest);
+        send(&shared, "a", None);
+        send(&shared, "b", Some("pos"));
+        assert_eq!(queued(&shared), ["a", "b"]);
+        send(&shared, "c", None);
+        send(&shared, "d", None);
+        assert_eq!(queued(&shared), ["c", "d"]);
+        assert!(!closed(&shared));
+    }
+
+    #[test]
+    fn coalesce_replaces_the_message_with_the_same_key() {
+        let shared = shared(Overflow::Coalesce);
+        send(&shared, "a", Some("pos"));
+        send(&shared, "b", Some("score"));
+        send(&shared, "c", Some("pos"));
+        assert_eq!(queued(&shared), ["b", "c"]);
+        send(&shared, "d", Some("score"));
+        assert_eq!(queued(&shared), ["c", "d"]);
+        assert!(!closed(&shared));
+    }
+
+    #[test]
+    fn coalesce_disconnects_when_no_key_matches() {
+        let shared = shared(Overflow::Coalesce);
+        send(&shared, "a", Some("pos"));
+        send(&shared, "b", None);
+        send(&shared, "c", Some("score"));
+        assert!(queued(&shared).is_empty());
+        assert!(closed(&shared));
+
+        let shared = self::shared(Overflow::Coalesce);
+        send(&shared, "a", Some("pos"));
+        send(&shared, "b", Some("pos"));
+        // messages without a key can't replace anything
+        send(&shared, "c", None);
+        assert!(queued(&shared).is_empty());
+        assert!(closed(&shared));
+    }
+
+    #[test]
+    fn disconnect() {
+        let shared = shared(Overflow::Disconnect);
+        send(&shared, "a", Some("pos"));
+        send(&shared, "b", None);
+        assert_eq!(queued(&shared), ["a", "b"]);
+        assert!(!closed(&shared));
+        send(&shared, "c", Some("pos"));
+        assert!(queued(&shared).is_empty());
+        assert!(closed(&shared));
+        // nothing is queued once the connection is closed
+        send(&shared, "d", None);
+        assert!(queued(&shared).is_empty());
+    }
+}
@@ -1,19 +1,57 @@
-use std::task::Poll;
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use futures_util::{SinkExt, TryStreamExt};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use rand::{distributions::Alphanumeric, Rng};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize};
+use serde::{Serialize, Serializer};
+use tokio::sync::{mpsc::error::TryRecvError, Notify};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::{
+    connection::{Connection, Outgoing},
+    handshake::{JoinError, JoinRequest},
+    matchmaking::RankedName,
+    rating::{elo, has_duplicates, Rating, RatingStore},
+};
 
 pub struct Lobby<S: LobbyState> {
     pub state: S,
     pub(crate) players: Vec<PlayerCon<S::PlayerState>>,
+    pub(crate) spectators: Vec<PlayerCon<S::SpectatorState>>,
+    /// players waiting for the game to end, see `LateJoin::Queue`
+    pub(crate) queued: Vec<PlayerCon<S::PlayerState>>,
+    /// Once there are this many players, new players are rejected with `JoinError::LobbyFull`.
+    /// Spectators don't count. Initially `LobbyState::MAX_PLAYERS`.
+    pub max_players: Option<usize>,
+    /// How many players are needed for a game, see `has_enough_players()`.
+    /// Initially `LobbyState::MIN_PLAYERS`.
+    pub min_players: usize,
+    /// true while a game is running
+    pub(crate) in_game: bool,
     /// set to true
     /// - manually
     /// - in new lobby
     /// - after playing a game
+    ///
     /// indicates that some state should be reset (player ready status, ...)
     pub reset: bool,
+    /// notified by the players' reader tasks when a message arrives
+    pub(crate) events: Arc<Notify>,
+    timers: Vec<(Instant, TimerId)>,
+    next_timer_id: u64,
+    ratings: Arc<dyn RatingStore>,
 }
 
 /// LobbyState is the state stored in every lobby.
@@ -23,31 +61,208 @@ pub struct Lobby<S: LobbyState> {
 /// When a new player joins a lobby, `new_player()` is called to generate an instance of `Self::PlayerState`.
 /// Then, player_joined() is called.
 ///
-/// When a player disconnects (this can only be detected after a call to `get_msg()` or `send()`),
-/// `player_leaving()` is called. After `player_leaving()`, the player is removed from `lobby.players`.
+/// When a player disconnects, or stops responding to pings (see `ServerBuilder::ping_timeout`),
+/// `player_leaving()` is called.
+/// After `player_leaving()`, the player is removed from `lobby.players`.
+/// If reconnecting is enabled, `player_disconnected()` is called first,
+/// and `player_leaving()` only if the player doesn't reconnect in time.
 ///
 /// `lobby_update()` is called repeatedly until it returns Some(_), which starts a game.
 /// Once the game finishes, the lobby's update loop restarts and `lobby.reset` is set to true.
+///
+/// Messages from players can be read by calling `get_msg()` on each player in `lobby_update()`,
+/// or, to react to them as soon as they arrive, by implementing `on_message()`.
+/// `on_tick()` is called right before every `lobby_update()`,
+/// and `on_timer()` when a timer started with `lobby.set_timer()` runs out.
+///
+/// Every lobby, and the games it plays, runs on its own task,
+/// so a slow `lobby_update()` or `GameState::update()` only delays its own lobby.
+///
+/// When the server shuts down (see `host_until`), `server_shutdown()` is called
+/// before all players are disconnected.
+///
+/// Clients can also join as spectators (see the `handshake` module).
+/// Spectators are kept in `lobby.spectators()` instead of `lobby.players()`,
+/// so they don't count towards "everyone is ready" checks,
+/// but they still receive `lobby.broadcast()`s.
+/// Between games, they can become players using `lobby.promote_spectator()`.
+/// Their messages go to `spectator_message()`, which keeps them for `spectator.get_msg()` by default,
+/// so either read them or override it, otherwise they pile up.
 #[async_trait]
 pub trait LobbyState: Send + Sized {
     /// State associated with each player,
     /// accessible via `player.data`.
     type PlayerState: Send;
+    /// State associated with each spectator, accessible via `spectator.data`.
+    /// Created using `Default` when a spectator joins. Use `()` if you don't need it.
+    type SpectatorState: Send + Default;
+    /// The default for `lobby.max_players` in new lobbies. Unlimited (`None`) by default.
+    const MAX_PLAYERS: Option<usize> = None;
+    /// The default for `lobby.min_players` in new lobbies. 0 by default.
+    const MIN_PLAYERS: usize = 0;
     /// This creates the default state/settings for a new lobby
     fn new() -> Self;
     /// This creates the default state for a newly joined player
     fn new_player() -> Self::PlayerState;
+    /// Called when someone tries to join, before `player_joined`.
+    /// Return an error to reject them, it is sent to the client
+    /// before the connection is closed. Everyone is accepted by default.
+    ///
+    /// Use this to check `request.password`, or to reject unsupported `request.client_version`s.
+    /// Not called for players who reconnect.
+    async fn accept_join(
+        _id: LobbyId,
+        _lobby: &mut Lobby<Self>,
+        _request: &JoinRequest,
+    ) -> Result<(), JoinError> {
+        Ok(())
+    }
     /// Called when a new player joins the lobby
-    async fn player_joined(id: usize, lobby: &mut Lobby<Self>, player: PlayerIndex);
+    async fn player_joined(id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex);
+    /// Called when a new spectator joins the lobby, also during a game.
+    async fn spectator_joined(_id: LobbyId, _lobby: &mut Lobby<Self>, _spectator: PlayerId) {}
+    /// Called when a spectator disconnects, before they are removed.
+    /// Spectators can't reconnect, so this is called right away.
+    async fn spectator_leaving(_id: LobbyId, _lobby: &mut Lobby<Self>, _spectator: PlayerId) {}
+    /// Called for every text message from a spectator as soon as it arrives, also during a game.
+    /// By default, the message is kept so that `spectator.get_msg()` can return it later.
+    /// Reading them is up to you, so if you don't need them, override this to drop them.
+    async fn spectator_message(
+        _id: LobbyId,
+        lobby: &mut Lobby<Self>,
+        spectator: PlayerId,
+        msg: String,
+    ) {
+        if let Some(spectator) = lobby.spectator(spectator) {
+            spectator.unread.push_back(Received::Text(msg));
+        }
+    }
+    /// Like `spectator_message()`, but for binary messages.
+    /// By default, the message is kept so that `spectator.try_recv()` can return it later.
+    async fn spectator_binary(
+        _id: LobbyId,
+        lobby: &mut Lobby<Self>,
+        spectator: PlayerId,
+        msg: Vec<u8>,
+    ) {
+        if let Some(spectator) = lobby.spectator(spectator) {
+            spectator.unread.push_back(Received::Binary(msg));
+        }
+    }
     /// Called when a player disconnects, before they are removed
-    async fn player_leaving(id: usize, lobby: &mut Lobby<Self>, player: PlayerIndex);
+    async fn player_leaving(id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerId);
+    /// Called when a player loses their connection, if reconnecting is enabled
+    /// (see `ServerBuilder::reconnect_grace`). The player stays in the lobby until they
+    /// reconnect (`player_reconnected()`) or the grace period ends (`player_leaving()`).
+    async fn player_disconnected(_id: LobbyId, _lobby: &mut Lobby<Self>, _player: PlayerId) {}
+    /// Called when a new connection resumed a player's session.
+    /// This can happen before `player_disconnected()`, if the server hadn't noticed yet
+    /// that the old connection was gone (see `ServerBuilder::ping_timeout`).
+    async fn player_reconnected(_id: LobbyId, _lobby: &mut Lobby<Self>, _player: PlayerId) {}
     /// Called repeatedly while in the lobby phase.
     /// Return Some(_) to start a game.
     /// Since you're returning a trait object (`dyn GameState`),
     /// you could create multiple structs implementing `GameState`
     /// and returning either one, possibly at random
     /// or based on `lobby.state` (user chooses a gamemode).
-    async fn lobby_update(id: usize, lobby: &mut Lobby<Self>) -> Option<Box<dyn GameState<Self>>>;
+    async fn lobby_update(id: LobbyId, lobby: &mut Lobby<Self>)
+        -> Option<Box<dyn GameState<Self>>>;
+    /// Called for every text message as soon as it arrives, while in the lobby phase.
+    /// By default, the message is kept so that `get_msg()` can return it later.
+    async fn on_message(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex, msg: String) {
+        lobby
+            .get_player(player)
+            .unread
+            .push_back(Received::Text(msg));
+    }
+    /// Like `on_message()`, but for binary messages.
+    /// By default, the message is kept so that `try_recv()` can return it later.
+    async fn on_binary(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex, msg: Vec<u8>) {
+        lobby
+            .get_player(player)
+            .unread
+            .push_back(Received::Binary(msg));
+    }
+    /// Called right before every `lobby_update()`.
+    /// `dt` is the time since the previous tick.
+    async fn on_tick(_id: LobbyId, _lobby: &mut Lobby<Self>, _dt: Duration) {}
+    /// Called when a timer started with `lobby.set_timer()` runs out while in the lobby phase.
+    async fn on_timer(_id: LobbyId, _lobby: &mut Lobby<Self>, _timer: TimerId) {}
+    /// Called when the server shuts down, before all players are disconnected.
+    /// If the lobby is in a game, `GameState::server_shutdown` is called first.
+    async fn server_shutdown(_id: LobbyId, _lobby: &mut Lobby<Self>) {}
+    /// Return `Some` to show this lobby in the public lobby list (see the `handshake` module).
+    /// Called after every event, so this should be cheap. Lobbies are private (`None`) by default.
+    fn listing(&self) -> Option<LobbyListing> {
+        None
+    }
+    /// How well a public lobby fits a player who wants to quickplay (see the `handshake` module).
+    /// The player joins the lobby with the highest score, or a new lobby if every lobby returns `None`.
+    /// Lobbies which are full or in a game aren't considered.
+    ///
+    /// Unlike the other hooks, this runs outside of the lobby, so it only gets the lobby's public entry.
+    /// `lobby.players` already includes players who are about to join.
+    /// By default, lobbies whose `mode` differs from the one the player asked for don't fit,
+    /// and fuller lobbies are preferred.
+    fn quickplay_score(lobby: &PublicLobby, request: &JoinRequest) -> Option<i64> {
+        if request
+            .mode
+            .as_ref()
+            .is_some_and(|mode| *mode != lobby.listing.mode)
+        {
+            return None;
+        }
+        Some(lobby.players as i64)
+    }
+}
+
+/// The messages a game's clients and server send, for `player.recv_msg()`, `player.send_msg()`
+/// and `lobby.broadcast_msg()`. Only with the `serde` feature.
+///
+/// ```ignore
+/// impl TypedLobbyState for GlobalState {
+///     type ClientMsg = ClientMsg;
+///     type ServerMsg = ServerMsg;
+/// }
+/// ```
+#[cfg(feature = "serde")]
+pub trait TypedLobbyState: LobbyState {
+    /// The messages clients send, which `player.recv_msg::<Self>()` decodes.
+    type ClientMsg: DeserializeOwned + Send;
+    /// The messages the server sends, which `player.send_msg::<Self>()` encodes using the player's `Codec`.
+    type ServerMsg: Serialize + Sync;
+}
+
+/// How a public lobby is shown in the lobby list, see `LobbyState::listing`.
+/// The player count, `max_players` and whether the lobby is in a game are added automatically.
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct LobbyListing {
+    pub title: String,
+    pub summary: String,
+    /// The game mode, which quickplay matches against the one the player asked for.
+    /// Leave it empty if your game doesn't have modes.
+    pub mode: String,
+    /// Anything else clients or `LobbyState::quickplay_score` should know about the lobby.
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub meta: serde_json::Value,
+}
+
+/// A lobby's entry in the public lobby list.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct PublicLobby {
+    pub code: LobbyId,
+    #[serde(flatten)]
+    pub listing: LobbyListing,
+    pub players: usize,
+    pub max_players: Option<usize>,
+    pub spectators: usize,
+    pub in_game: bool,
+}
+
+impl PublicLobby {
+    pub fn is_full(&self) -> bool {
+        self.max_players.is_some_and(|max| self.players >= max)
+    }
 }
 
 /// GameState is the state used during a game.
@@ -55,29 +270,151 @@ pub trait LobbyState: Send + Sized {
 /// During a game, `update()` is called repeatedly.
 /// Once `update()` returns `true`, the game ends.
 /// You can send/receive messages from the clients using `lobby`.
+/// Like in `LobbyState`, you can also react to messages and timers using
+/// `on_message()`, `on_tick()` and `on_timer()`.
 #[async_trait]
 pub trait GameState<S: LobbyState>: Send {
     // update your game.
     // return true to end the game and return to the lobby.
     async fn update(&mut self, lobby: &mut Lobby<S>) -> bool;
-    async fn player_leaving(&mut self, lobby: &mut Lobby<S>, player: PlayerIndex);
+    /// What happens to players who want to join the lobby during this game.
+    /// `LobbyState::accept_join` is called first either way. Rejects them by default.
+    fn late_join(&self) -> LateJoin {
+        LateJoin::Reject
+    }
+    /// Called when a player joins during this game, if `late_join()` returned `LateJoin::Join`.
+    async fn player_joined(&mut self, _lobby: &mut Lobby<S>, _player: PlayerIndex) {}
+    async fn player_leaving(&mut self, lobby: &mut Lobby<S>, player: PlayerId);
+    /// Like `LobbyState::player_disconnected`, but during this game.
+    /// Use this to pause the game instead of ending it.
+    async fn player_disconnected(&mut self, _lobby: &mut Lobby<S>, _player: PlayerId) {}
+    /// Like `LobbyState::player_reconnected`, but during this game.
+    async fn player_reconnected(&mut self, _lobby: &mut Lobby<S>, _player: PlayerId) {}
+    /// Called for every text message as soon as it arrives, during this game.
+    /// By default, the message is kept so that `get_msg()` can return it later.
+    async fn on_message(&mut self, lobby: &mut Lobby<S>, player: PlayerIndex, msg: String) {
+        lobby
+            .get_player(player)
+            .unread
+            .push_back(Received::Text(msg));
+    }
+    /// Like `on_message()`, but for binary messages.
+    /// By default, the message is kept so that `try_recv()` can return it later.
+    async fn on_binary(&mut self, lobby: &mut Lobby<S>, player: PlayerIndex, msg: Vec<u8>) {
+        lobby
+            .get_player(player)
+            .unread
+            .push_back(Received::Binary(msg));
+    }
+    /// Called right before every `update()`.
+    /// `dt` is the time since the previous tick.
+    async fn on_tick(&mut self, _lobby: &mut Lobby<S>, _dt: Duration) {}
+    /// Called when a timer started with `lobby.set_timer()` runs out during this game.
+    async fn on_timer(&mut self, _lobby: &mut Lobby<S>, _timer: TimerId) {}
+    /// Called when the server shuts down during this game, before all players are disconnected.
+    async fn server_shutdown(&mut self, _lobby: &mut Lobby<S>) {}
 }
 
-pub(crate) struct InGame<S: LobbyState> {
-    pub(crate) lobby: Lobby<S>,
-    pub(crate) game_state: Box<dyn GameState<S>>,
+/// See `GameState::late_join`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LateJoin {
+    /// Reject them with `JoinError::GameInProgress`.
+    Reject,
+    /// Let them wait until the game ends, then they join the lobby
+    /// and `LobbyState::player_joined` is called.
+    Queue,
+    /// Let them join the running game. `GameState::player_joined` is called,
+    /// and they stay in the lobby once the game ends.
+    Join,
+    /// Let them join as a spectator. `LobbyState::spectator_joined` is called.
+    /// Between games, you can use `lobby.promote_spectator()` to make them a player.
+    Spectate,
 }
 
 pub struct PlayerCon<D> {
     pub data: D,
-    con: Option<WebSocketStream<TcpStream>>,
+    con: Connection,
+    /// messages which were received, but not yet returned by `get_msg()` or `try_recv()`
+    unread: VecDeque<Received>,
+    join: JoinRequest,
+    id: PlayerId,
+    session: String,
+    /// when the player disconnected, if they are waiting to reconnect
+    pub(crate) disconnected_since: Option<Instant>,
+    /// the `rid` of the message which was received last, see `reply()`
+    last_request: Option<u64>,
+    /// keeps other ranked players from using the same name, see `set_ranked()`
+    ranked_name: Option<RankedName>,
 }
 
+/// Identifies a timer started with `lobby.set_timer()`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TimerId(u64);
+
 /// Index of a player that exists.
 /// Allows you to use `lobby.get_player()` without dealing with the index-out-of-bounds cases,
-/// since this index is never out-of-bounds.
+/// since this index is never out-of-bounds while the hook you got it from is running.
+/// Once a player leaves, the indices of the players after them change,
+/// so if you want to remember a player, use their `PlayerId` instead.
 #[derive(Clone, Copy)]
 pub struct PlayerIndex(pub(crate) usize);
+
+/// Identifies a player for as long as they are connected (or reconnecting).
+/// Unlike `PlayerIndex`, this doesn't change when other players leave,
+/// but the player might be gone, so `lobby.player(id)` returns an `Option`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PlayerId(u64);
+
+static NEXT_PLAYER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The code players use to join a lobby, like `KXQF`.
+/// Codes are random, and a lobby's code isn't given to a new lobby until a while after it closed,
+/// see `ServerBuilder::lobby_code_reuse_delay`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LobbyId {
+    code: [u8; LobbyId::MAX_LEN],
+    len: u8,
+}
+
+impl LobbyId {
+    /// The maximum length of a lobby code.
+    pub const MAX_LEN: usize = 16;
+    /// `None` if `code` is empty, too long, or not ascii.
+    pub(crate) fn new(code: &str) -> Option<Self> {
+        if code.is_empty() || code.len() > Self::MAX_LEN || !code.is_ascii() {
+            return None;
+        }
+        let mut id = Self {
+            code: [0; Self::MAX_LEN],
+            len: code.len() as u8,
+        };
+        id.code[..code.len()].copy_from_slice(code.as_bytes());
+        Some(id)
+    }
+    pub fn as_str(&self) -> &str {
+        // only ever created from a `&str` in `new`
+        std::str::from_utf8(&self.code[..self.len as usize]).unwrap()
+    }
+}
+
+impl Display for LobbyId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for LobbyId {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl Debug for LobbyId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("LobbyId").field(&self.as_str()).finish()
+    }
+}
+
 impl PlayerIndex {
     pub fn i(&self) -> usize {
         self.0
@@ -85,11 +422,20 @@ impl PlayerIndex {
 }
 
 impl<S: LobbyState> Lobby<S> {
-    pub(crate) fn new(settings: S, players: Vec<PlayerCon<S::PlayerState>>) -> Self {
+    pub(crate) fn new(settings: S, ratings: Arc<dyn RatingStore>) -> Self {
         Self {
             state: settings,
-            players,
+            players: vec![],
+            spectators: vec![],
+            queued: vec![],
+            max_players: S::MAX_PLAYERS,
+            min_players: S::MIN_PLAYERS,
+            in_game: false,
             reset: true,
+            events: Arc::new(Notify::new()),
+            timers: vec![],
+            next_timer_id: 0,
+            ratings,
         }
     }
     pub(crate) fn join(&mut self, player: PlayerCon<S::PlayerState>) {
@@ -98,98 +444,582 @@ impl<S: LobbyState> Lobby<S> {
     pub fn get_player(&mut self, player: PlayerIndex) -> &mut PlayerCon<S::PlayerState> {
         &mut self.players[player.0]
     }
+    /// The player with this id, if they are still in the lobby.
+    pub fn player(&mut self, player: PlayerId) -> Option<&mut PlayerCon<S::PlayerState>> {
+        self.players.iter_mut().find(|p| p.id == player)
+    }
+    /// The current index of the player with this id, if they are still in the lobby.
+    pub fn player_index(&self, player: PlayerId) -> Option<PlayerIndex> {
+        self.players
+            .iter()
+            .position(|p| p.id == player)
+            .map(PlayerIndex)
+    }
+    pub fn player_ids(&self) -> impl Iterator<Item = PlayerId> + '_ {
+        self.players.iter().map(|p| p.id)
+    }
     pub fn players(&self) -> &Vec<PlayerCon<S::PlayerState>> {
         &self.players
     }
-    pub fn players_mut(&mut self) -> std::slice::IterMut<PlayerCon<S::PlayerState>> {
+    pub fn players_mut(&mut self) -> std::slice::IterMut<'_, PlayerCon<S::PlayerState>> {
         self.players.iter_mut()
     }
     pub fn player_indices(&self) -> impl Iterator<Item = PlayerIndex> {
-        (0..self.players.len()).map(|v| PlayerIndex(v))
+        (0..self.players.len()).map(PlayerIndex)
     }
-}
-
-impl<S: LobbyState> InGame<S> {
-    pub(crate) fn new(lobby: Lobby<S>, game_state: Box<dyn GameState<S>>) -> Self {
-        Self { lobby, game_state }
+    /// True if no more players can join because of `max_players`.
+    /// Players who are waiting for the current game to end count, too.
+    pub fn is_full(&self) -> bool {
+        self.max_players
+            .is_some_and(|max| self.players.len() + self.queued.len() >= max)
+    }
+    /// True if there are at least `min_players` players.
+    pub fn has_enough_players(&self) -> bool {
+        self.players.len() >= self.min_players
+    }
+    pub(crate) fn join_spectator(&mut self, spectator: PlayerCon<S::SpectatorState>) {
+        self.spectators.push(spectator);
+    }
+    /// The spectator with this id, if they are still in the lobby.
+    pub fn spectator(&mut self, spectator: PlayerId) -> Option<&mut PlayerCon<S::SpectatorState>> {
+        self.spectators.iter_mut().find(|p| p.id == spectator)
+    }
+    pub fn spectators(&self) -> &Vec<PlayerCon<S::SpectatorState>> {
+        &self.spectators
+    }
+    pub fn spectators_mut(&mut self) -> std::slice::IterMut<'_, PlayerCon<S::SpectatorState>> {
+        self.spectators.iter_mut()
+    }
+    /// Turns a spectator into a player with a new `PlayerState`, keeping their `PlayerId`.
+    /// `player_joined` is not called.
+    ///
+    /// Returns `None` if the spectator doesn't exist, the lobby is full, or a game is running.
+    pub fn promote_spectator(&mut self, spectator: PlayerId) -> Option<PlayerIndex> {
+        if self.in_game || self.is_full() {
+            return None;
+        }
+        let index = self.spectators.iter().position(|p| p.id == spectator)?;
+        let player = self.spectators.remove(index).with_data(S::new_player());
+        self.players.push(player);
+        Some(PlayerIndex(self.players.len() - 1))
+    }
+    /// Turns a player into a spectator, keeping their `PlayerId`.
+    /// `player_leaving` is not called.
+    ///
+    /// Returns `false` if the player doesn't exist, is disconnected, or if a game is running.
+    pub fn demote_player(&mut self, player: PlayerId) -> bool {
+        if self.in_game {
+            return false;
+        }
+        let Some(index) = self
+            .players
+            .iter()
+            .position(|p| p.id == player && !p.disconnected())
+        else {
+            return false;
+        };
+        let spectator = self.players.remove(index).with_data(Default::default());
+        self.spectators.push(spectator);
+        true
+    }
+    /// Sends `msg` to all players and spectators.
+    ///
+    /// Like all `send` and `broadcast` functions, this only puts the message into
+    /// every connection's outbound queue (see `ServerBuilder::outbound_queue`),
+    /// so one slow connection doesn't delay the others.
+    /// The queues share one copy of `msg`. tungstenite needs its own copy for every message it sends,
+    /// so each connection only copies `msg` right before sending it.
+    pub async fn broadcast(&mut self, msg: String) {
+        let cons = self.players.iter().map(|p| &p.con);
+        let cons = cons.chain(self.spectators.iter().map(|s| &s.con));
+        send_to_all(cons, Message::Text(msg));
+    }
+    /// Sends `msg` to all players, but not to spectators.
+    pub async fn broadcast_players(&mut self, msg: String) {
+        send_to_all(self.players.iter().map(|p| &p.con), Message::Text(msg));
+    }
+    /// Sends `msg` to all spectators, but not to players.
+    pub async fn broadcast_spectators(&mut self, msg: String) {
+        send_to_all(self.spectators.iter().map(|s| &s.con), Message::Text(msg));
+    }
+    /// Sends `msg` to all players and spectators except `except`,
+    /// for example to tell everyone else what a player did.
+    pub async fn broadcast_except(&mut self, except: PlayerId, msg: String) {
+        let cons = self
+            .players
+            .iter()
+            .filter(|p| p.id != except)
+            .map(|p| &p.con);
+        let cons = cons.chain(
+            self.spectators
+                .iter()
+                .filter(|s| s.id != except)
+                .map(|s| &s.con),
+        );
+        send_to_all(cons, Message::Text(msg));
+    }
+    /// Sends every player the message `msg` returns for them, but nothing to spectators.
+    pub async fn broadcast_with(
+        &mut self,
+        mut msg: impl FnMut(&PlayerCon<S::PlayerState>) -> String,
+    ) {
+        for player in self.players.iter() {
+            player.con.send(Message::Text(msg(player)));
+        }
+    }
+    /// Like `broadcast`, but sends `msg` using every player's `Codec`.
+    /// It is only encoded once per codec.
+    #[cfg(feature = "serde")]
+    pub async fn broadcast_msg(&mut self, msg: &S::ServerMsg)
+    where
+        S: TypedLobbyState,
+    {
+        let mut encoded = Vec::<(Codec, Option<Outgoing>)>::new();
+        let mut encode = |codec: Codec| {
+            if let Some((_, msg)) = encoded.iter().find(|(c, _)| *c == codec) {
+                return msg.as_ref().map(Outgoing::share);
+            }
+            let msg = codec.encode(msg).map(Outgoing::shared);
+            let copy = msg.as_ref().map(Outgoing::share);
+            encoded.push((codec, msg));
+            copy
+        };
+        let players = self.players.iter().map(|p| (&p.con, p.join.codec));
+        let spectators = self.spectators.iter().map(|s| (&s.con, s.join.codec));
+        for (con, codec) in players.chain(spectators) {
+            if let Some(msg) = encode(codec) {
+                con.send(msg);
+            }
+        }
+    }
+    /// Starts a timer. Once it runs out, `on_timer()` is called
+    /// on the `GameState` if a game is running, or on the `LobbyState` otherwise.
+    pub fn set_timer(&mut self, after: Duration) -> TimerId {
+        let id = TimerId(self.next_timer_id);
+        self.next_timer_id += 1;
+        self.timers.push((Instant::now() + after, id));
+        id
+    }
+    /// Stops a timer before it runs out.
+    /// Returns false if the timer doesn't exist or already ran out.
+    pub fn cancel_timer(&mut self, timer: TimerId) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|(_, id)| *id != timer);
+        self.timers.len() != len
     }
-    pub(crate) fn into_lobby(mut self) -> Lobby<S> {
-        self.lobby.reset = true;
-        self.lobby
+    /// when the next timer runs out
+    pub(crate) fn next_timer(&self) -> Option<Instant> {
+        self.timers.iter().map(|(at, _)| *at).min()
     }
-    pub(crate) async fn update(&mut self) -> bool {
-        self.game_state.update(&mut self.lobby).await
+    /// removes and returns all timers which have run out, in the order they ran out
+    pub(crate) fn expired_timers(&mut self) -> Vec<TimerId> {
+        let now = Instant::now();
+        let mut expired = self
+            .timers
+            .iter()
+            .filter(|(at, _)| *at <= now)
+            .copied()
+            .collect::<Vec<_>>();
+        self.timers.retain(|(at, _)| *at > now);
+        expired.sort_by_key(|(at, _)| *at);
+        expired.into_iter().map(|(_, id)| id).collect()
     }
-    pub(crate) async fn player_leaving(&mut self, index: usize) {
-        self.game_state
-            .player_leaving(&mut self.lobby, PlayerIndex(index))
-            .await;
+    /// Updates the players' ratings after a rated match, see the `rating` module.
+    /// `result` has every player's name and place, where 0 is the winner and equal places are draws.
+    /// Returns the new ratings, in the same order,
+    /// or None without changing any rating if a name appears more than once.
+    ///
+    /// Ratings belong to names, and names are not authenticated: anyone can join as "Mark",
+    /// ranked or not, and change Mark's rating. Ranked matchmaking only makes sure that
+    /// a name isn't in two ranked games at once. If that matters, check who players are
+    /// in `LobbyState::accept_join`, for example using `join_request().password`.
+    pub async fn report_result(&self, result: &[(&str, u32)]) -> Option<Vec<Rating>> {
+        let names = result.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        if has_duplicates(&names) {
+            return None;
+        }
+        let update = |old: &[Rating]| {
+            let players = old
+                .iter()
+                .zip(result)
+                .map(|(rating, (_, place))| (*rating, *place))
+                .collect::<Vec<_>>();
+            elo(&players)
+        };
+        Some(self.ratings.update(&names, &update).await)
+    }
+    /// The rating of the player with this name, see the `rating` module.
+    pub async fn rating(&self, name: &str) -> Rating {
+        self.ratings.get(name).await
+    }
+    /// ids of all disconnected players
+    pub(crate) fn disconnected_players(&self) -> Vec<PlayerId> {
+        self.players
+            .iter()
+            .filter(|p| p.disconnected())
+            .map(|p| p.id)
+            .collect()
+    }
+    /// the index of the player with this session token.
+    /// Their connection may still look alive, see `LobbyTask::resume`.
+    pub(crate) fn find_session(&self, token: &str) -> Option<usize> {
+        self.players.iter().position(|p| p.session == token)
+    }
+    /// disconnects all players and spectators, telling them that the server is shutting down.
+    pub(crate) async fn close_all(&mut self) {
+        for player in self.players.iter_mut() {
+            player.close_shutdown().await;
+        }
+        for spectator in self.spectators.iter_mut() {
+            spectator.close_shutdown().await;
+        }
+        for player in self.queued.iter_mut() {
+            player.close_shutdown().await;
+        }
     }
 }
 
 impl<D> PlayerCon<D> {
-    pub(crate) fn new(data: D, con: WebSocketStream<TcpStream>) -> Self {
+    pub(crate) fn new(data: D, join: JoinRequest, con: Connection) -> Self {
         Self {
             data,
-            con: Some(con),
+            con,
+            unread: VecDeque::new(),
+            join,
+            id: PlayerId(NEXT_PLAYER_ID.fetch_add(1, Ordering::Relaxed)),
+            session: rand::thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect(),
+            disconnected_since: None,
+            last_request: None,
+            ranked_name: None,
         }
     }
+    pub fn id(&self) -> PlayerId {
+        self.id
+    }
+    /// see `Connection::set_events`
+    pub(crate) fn set_events(&self, events: Arc<Notify>) {
+        self.con.set_events(events);
+    }
+    /// the same connection, with different data (used to turn spectators into players and back)
+    pub(crate) fn with_data<E>(self, data: E) -> PlayerCon<E> {
+        PlayerCon {
+            data,
+            con: self.con,
+            unread: self.unread,
+            join: self.join,
+            id: self.id,
+            session: self.session,
+            disconnected_since: self.disconnected_since,
+            last_request: self.last_request,
+            ranked_name: self.ranked_name,
+        }
+    }
+    /// What the client sent in its handshake (name, password, ...).
+    pub fn join_request(&self) -> &JoinRequest {
+        &self.join
+    }
+    /// marks the player as matched by ranked matchmaking (clients can't set `JoinRequest::ranked` themselves).
+    /// Their name stays reserved until they are dropped.
+    pub(crate) fn set_ranked(&mut self, name: RankedName) {
+        self.join.ranked = true;
+        self.ranked_name = Some(name);
+    }
+    /// A secret token which identifies this player.
+    /// If reconnecting is enabled (see `ServerBuilder::reconnect_grace`),
+    /// a client can use it in its handshake to take over this player after losing its connection,
+    /// so you should send this token to the client.
+    pub fn session_token(&self) -> &str {
+        &self.session
+    }
+    /// If the client asked for it in its handshake (the JavaScript client does, see `js`),
+    /// tells it that it is now in the lobby `lobby`, and which session token it can use to reconnect.
+    /// `resumable` is false if reconnecting is disabled. Spectators can't reconnect either.
+    pub(crate) fn welcome(&self, lobby: LobbyId, spectator: bool, resumable: bool, resumed: bool) {
+        if !self.join.welcome {
+            return;
+        }
+        let msg = serde_json::json!({
+            "ez": "welcome",
+            "lobby": lobby.to_string(),
+            "token": (resumable && !spectator).then_some(&self.session),
+            "spectator": spectator,
+            "resumed": resumed,
+        });
+        self.con.send(Message::Text(msg.to_string()));
+    }
+    /// replaces the player's connection, returning the old one.
+    /// `welcome` is what the new connection's handshake asked for.
+    pub(crate) fn reconnect(&mut self, con: Connection, welcome: bool) -> Connection {
+        self.join.welcome = welcome;
+        self.disconnected_since = None;
+        std::mem::replace(&mut self.con, con)
+    }
     /// forcibly disconnects this player.
     pub async fn force_disconnect(&mut self) {
-        if let Some(con) = &mut self.con {
-            _ = con.close(None).await;
-            self.con = None;
+        self.con.close(None).await;
+    }
+    /// disconnects a player who couldn't join, telling them why.
+    pub(crate) async fn reject(&mut self, error: &JoinError) {
+        self.con.close(Some(error.close_frame())).await;
+    }
+    /// disconnects this player with a close frame saying that the server is shutting down.
+    pub(crate) async fn close_shutdown(&mut self) {
+        if !self.con.is_closed() {
+            self.con.close(Some(shutdown_close_frame())).await;
         }
     }
+    /// true once the player has disconnected or stopped responding to pings.
+    /// If reconnecting is enabled, this can become false again if the player reconnects.
     pub fn disconnected(&self) -> bool {
-        self.con.is_none()
+        self.con.is_closed()
     }
     pub async fn send(&mut self, msg: String) {
-        if let Some(con) = &mut self.con {
-            if con.send(Message::Text(msg)).await.is_err() {
-                self.con = None;
+        self.con.send(Message::Text(msg));
+    }
+    /// Like `send`, for messages which only contain the latest state of something, like a player's position.
+    /// With `Overflow::Coalesce`, if the player's outbound queue is full,
+    /// this replaces the queued message with the same `key`, which is outdated anyway.
+    pub async fn send_keyed(&mut self, key: impl Into<String>, msg: String) {
+        self.con.send_keyed(key.into(), Message::Text(msg));
+    }
+    pub async fn send_binary(&mut self, msg: Vec<u8>) {
+        self.con.send(Message::Binary(msg));
+    }
+    /// Like `send`, but answers the message which was received from this player last.
+    /// If that was a request (it has a numeric `rid` field, which `client.request()` in the
+    /// JavaScript client adds), and `msg` is a JSON object, a `"re"` field with the same number
+    /// is added to `msg`, so the client knows which request this is the response to.
+    pub async fn reply(&mut self, msg: String) {
+        let msg = match self.last_request {
+            Some(id) if msg.trim_start().starts_with('{') => {
+                let fields = msg.trim_start()[1..].trim_start();
+                if fields.starts_with('}') {
+                    format!("{{\"re\":{id}{fields}")
+                } else {
+                    format!("{{\"re\":{id},{fields}")
+                }
             }
-        }
+            _ => msg,
+        };
+        self.send(msg).await
+    }
+    /// How many messages are waiting to be sent to this player.
+    /// If this keeps growing, the player's connection can't keep up.
+    pub fn queued_messages(&self) -> usize {
+        self.con.queue_len()
     }
     // like `get_msg`, but blocking
     pub async fn wait_for_msg(&mut self) -> Option<String> {
-        if let Some(con) = &mut self.con {
-            if let Ok(Some(msg)) = con.try_next().await {
-                self.respond_msg(msg).await
-            } else {
-                None
+        while let Some(msg) = self.recv().await {
+            if let Received::Text(msg) = msg {
+                return Some(msg);
             }
-        } else {
-            None
         }
+        None
     }
+    /// The next text message, if one was received. Binary messages before it are skipped,
+    /// use `try_recv()` if you want them.
     pub async fn get_msg(&mut self) -> Option<String> {
-        if let Some(con) = &mut self.con {
-            if let Poll::Ready(Ok(Some(msg))) = futures_util::poll!(con.try_next()) {
-                self.respond_msg(msg).await
-            } else {
-                None
+        while let Some(msg) = self.try_recv().await {
+            if let Received::Text(msg) = msg {
+                return Some(msg);
             }
-        } else {
-            None
         }
+        None
     }
-    async fn respond_msg(&mut self, msg: Message) -> Option<String> {
-        match msg {
-            Message::Text(msg) => Some(msg),
-            Message::Close(_) => {
-                self.force_disconnect().await;
-                None
+    /// like `try_recv`, but blocking
+    pub async fn recv(&mut self) -> Option<Received> {
+        if let Some(msg) = self.unread.pop_front() {
+            self.last_request = request_id(&msg);
+            return Some(msg);
+        }
+        while let Some(msg) = self.con.recv().await {
+            if let Some(msg) = self.respond_msg(msg).await {
+                return Some(msg);
             }
-            Message::Binary(_) => None,
-            Message::Ping(data) => {
-                if let Some(con) = &mut self.con {
-                    _ = con.send(Message::Pong(data));
+        }
+        None
+    }
+    /// The next message, text or binary, if one was received.
+    pub async fn try_recv(&mut self) -> Option<Received> {
+        if let Some(msg) = self.unread.pop_front() {
+            self.last_request = request_id(&msg);
+            return Some(msg);
+        }
+        self.recv_new().await
+    }
+    /// returns the next message from the reader task, ignoring `unread`.
+    pub(crate) async fn recv_new(&mut self) -> Option<Received> {
+        loop {
+            match self.con.try_recv() {
+                Ok(msg) => {
+                    if let Some(msg) = self.respond_msg(msg).await {
+                        return Some(msg);
+                    }
+                }
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    self.force_disconnect().await;
+                    return None;
                 }
-                None
             }
-            Message::Pong(_) => None,
-            Message::Frame(_) => None,
         }
     }
+    /// Sends one of your `TypedLobbyState::ServerMsg`s using the codec the player chose in their handshake.
+    /// `S` is your `TypedLobbyState`, so inside of its impl, use `player.send_msg::<Self>(&msg)`.
+    #[cfg(feature = "serde")]
+    pub async fn send_msg<S: TypedLobbyState>(&mut self, msg: &S::ServerMsg) {
+        self.send_encoded(msg);
+    }
+    /// Like `send_msg`, but answers the message which was received from this player last, see `reply()`.
+    /// `msg` must serialize to a map (like a struct) for the `"re"` field to be added.
+    #[cfg(feature = "serde")]
+    pub async fn reply_msg<S: TypedLobbyState>(&mut self, msg: &S::ServerMsg) {
+        let Some(id) = self.last_request else {
+            return self.send_encoded(msg);
+        };
+        match serde_json::to_value(msg) {
+            Ok(serde_json::Value::Object(mut fields)) => {
+                fields.insert("re".to_owned(), id.into());
+                self.send_encoded(&fields);
+            }
+            _ => self.send_encoded(msg),
+        }
+    }
+    #[cfg(feature = "serde")]
+    fn send_encoded<M: Serialize + ?Sized>(&self, msg: &M) {
+        if let Some(msg) = self.join.codec.encode(msg) {
+            self.con.send(msg);
+        }
+    }
+    /// Like `try_recv`, but decodes the message into one of your `TypedLobbyState::ClientMsg`s.
+    /// `S` is your `TypedLobbyState`, so inside of its impl, use `player.recv_msg::<Self>()`.
+    /// Text messages are decoded as JSON, binary messages as MessagePack (with the `msgpack` feature).
+    /// Messages which aren't valid are returned as `Err`, so you can ignore them or kick the player.
+    #[cfg(feature = "serde")]
+    pub async fn recv_msg<S: TypedLobbyState>(
+        &mut self,
+    ) -> Option<Result<S::ClientMsg, DecodeError>> {
+        let msg = self.try_recv().await?;
+        let decoded = match &msg {
+            Received::Text(text) => serde_json::from_str(text).map_err(Into::into),
+            #[cfg(feature = "msgpack")]
+            Received::Binary(data) => rmp_serde::from_slice(data).map_err(Into::into),
+            #[cfg(not(feature = "msgpack"))]
+            Received::Binary(_) => Err("binary messages need the msgpack feature".into()),
+        };
+        Some(decoded.map_err(|error| DecodeError { msg, error }))
+    }
+    async fn respond_msg(&mut self, msg: Message) -> Option<Received> {
+        let msg = match msg {
+            Message::Text(msg) => Received::Text(msg),
+            Message::Binary(msg) => Received::Binary(msg),
+            Message::Close(_) => {
+                self.force_disconnect().await;
+                return None;
+            }
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => return None,
+        };
+        self.last_request = request_id(&msg);
+        Some(msg)
+    }
+}
+
+/// the `rid` field of a JSON object, see `PlayerCon::reply()`
+fn request_id(msg: &Received) -> Option<u64> {
+    match msg {
+        // most messages aren't requests, so don't parse those
+        Received::Text(text) if text.contains("\"rid\"") => {
+            serde_json::from_str::<serde_json::Value>(text)
+                .ok()?
+                .get("rid")?
+                .as_u64()
+        }
+        _ => None,
+    }
+}
+
+/// A message a player sent, see `player.try_recv()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Received {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A message which `recv_msg()` couldn't decode.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct DecodeError {
+    /// The message as it was received.
+    pub msg: Received,
+    pub error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+#[cfg(feature = "serde")]
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid message: {}", self.error)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// How typed messages (see `TypedLobbyState`) are sent to a player.
+/// Clients choose one using `codec` in their handshake, see the `handshake` module.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// JSON in text messages
+    #[default]
+    Json,
+    /// MessagePack in binary messages. Only with the `msgpack` feature.
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+#[cfg(feature = "serde")]
+impl Codec {
+    /// `None` if `msg` can't be encoded, for example a map with non-string keys in JSON.
+    fn encode<M: Serialize + ?Sized>(self, msg: &M) -> Option<Message> {
+        match self {
+            Self::Json => serde_json::to_string(msg).ok().map(Message::Text),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack => rmp_serde::to_vec_named(msg).ok().map(Message::Binary),
+        }
+    }
+}
+
+/// queues `msg` on all connections. The queues share one copy of it, see `Outgoing`.
+fn send_to_all<'a>(cons: impl Iterator<Item = &'a Connection>, msg: Message) {
+    let msg = Outgoing::shared(msg);
+    for con in cons {
+        con.send(msg.share());
+    }
+}
+
+/// sent to all players when the server shuts down
+pub(crate) fn shutdown_close_frame() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: "server shutting down".into(),
+    }
+}
+
+/// sent to a player's old connection when a new one resumes their session
+pub(crate) fn resumed_close_frame() -> CloseFrame<'static> {
+    CloseFrame {
+        // next to the codes of `JoinError`, which the JavaScript client doesn't retry either
+        code: CloseCode::from(4006),
+        reason: "session resumed elsewhere".into(),
+    }
 }
@@ -0,0 +1,331 @@
+//! The first message a client sends after connecting, which says which lobby it wants to join.
+//!
+//! The handshake is a JSON object:
+//!
+//! ```json
+//! { "v": 1, "lobby": "KXQF", "name": "Mark", "password": "hunter2", "client_version": "1.0.3", "meta": { "color": "red" } }
+//! ```
+//!
+//! - `v` must be `PROTOCOL_VERSION`.
+//! - `lobby` is the code of the lobby to join. Leave it out to create a new lobby.
+//! - `spectate: true` joins as a spectator instead of a player.
+//! - `quickplay: true` (without `lobby`) joins the best fitting public lobby, see `LobbyState::quickplay_score`,
+//!   or creates a new lobby if none fits. Add `mode` to only join lobbies with that game mode.
+//! - `ranked: true` (without `lobby`) waits for players with a similar rating, see the `rating` module.
+//!   This needs a `name`.
+//! - With the `msgpack` feature, `codec: "msgpack"` makes `player.send_msg()` send MessagePack in binary messages
+//!   instead of JSON in text messages.
+//! - `name`, `password`, `client_version` and `meta` are optional.
+//!   They are passed to `LobbyState::accept_join` as a `JoinRequest`, and can later be read using `player.join_request()`.
+//! - `welcome: true` makes the server send a message with the lobby code and session token once the client joined,
+//!   see the `js` module, whose JavaScript client does all of this.
+//! - To reconnect (see `ServerBuilder::reconnect_grace`), send `{ "v": 1, "resume": "<session token>" }` instead.
+//!   Add `welcome: true` to be told once the connection took over the player.
+//!
+//! To get the list of public lobbies (see `LobbyState::listing`) without joining one,
+//! send `{ "v": 1, "list": true }`. The server replies with one message, then closes the connection:
+//!
+//! ```json
+//! { "lobbies": [{ "code": "KXQF", "title": "...", "summary": "...", "mode": "", "players": 3, "max_players": 8, "spectators": 0, "in_game": false }] }
+//! ```
+//!
+//! `max_players` is `null` if there is no limit. Lobbies which set `meta` in their `LobbyListing` also have a `meta` field.
+//!
+//! For simple clients, the plain text messages `new`, `quickplay`, `list`, `<lobby code>` and `resume:<token>`
+//! also work, and are treated like a handshake without a name or anything else.
+//!
+//! If the client can't join, the server closes the connection with a close frame
+//! whose code and reason describe the problem, see `JoinError`.
+//! When a new connection resumes a player's session, their old connection is closed with code 4006.
+
+use std::{borrow::Cow, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+use crate::{
+    game::{LobbyId, PublicLobby},
+    server::Config,
+};
+
+/// The version of the handshake format described in this module.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a client sent when it tried to join a lobby.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct JoinRequest {
+    /// The player's display name. Empty if the client didn't send one.
+    pub name: String,
+    /// The lobby password the player entered, if any.
+    /// Checking it is up to `LobbyState::accept_join`.
+    pub password: Option<String>,
+    /// The version of the client, if it sent one.
+    pub client_version: Option<String>,
+    /// Anything else the client wanted to send. `null` if it didn't send anything.
+    pub meta: serde_json::Value,
+    /// True if the client wants to join as a spectator.
+    pub spectate: bool,
+    /// True if the client asked to be put into any fitting lobby, see `LobbyState::quickplay_score`.
+    /// If this player created the lobby, you may want to make it public so that other quickplayers can join.
+    pub quickplay: bool,
+    /// The game mode the player wants to play, if they asked for one. Used by quickplay.
+    pub mode: Option<String>,
+    /// True if the player joined through ranked matchmaking, see the `rating` module.
+    /// The other players who were matched with them join the same new lobby right after them.
+    /// Clients can only ask for matchmaking with this: it is always false for other handshakes.
+    pub ranked: bool,
+    /// True if the client wants to be told when it joined a lobby, see the module documentation.
+    /// After reconnecting, this is what the handshake of the new connection asked for.
+    pub welcome: bool,
+    /// How the player wants to receive typed messages. Only with the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub codec: crate::game::Codec,
+}
+
+/// Why a client couldn't join a lobby.
+///
+/// The client receives this as the code and reason of the WebSocket's close frame,
+/// so in a browser, check `event.code` and `event.reason` in `onclose`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The first message wasn't a valid handshake, or didn't arrive in time. Code 4000.
+    BadHandshake,
+    /// There is no lobby with this code. Code 4001.
+    LobbyNotFound,
+    /// The session to resume doesn't exist (anymore). Code 4002.
+    SessionNotFound,
+    /// The lobby doesn't accept any more players. Code 4003.
+    LobbyFull,
+    /// The lobby is in a game and doesn't let players join right now. Code 4004.
+    GameInProgress,
+    /// A new lobby can't be created because the server has too many. Code 4005.
+    TooManyLobbies,
+    /// The game rejected the player, for example because of a wrong password. Code 4010.
+    Rejected(String),
+    /// The game rejected the player with its own code, which should be in 4100..=4999
+    /// so it doesn't clash with the codes above. Other codes are replaced with 4010.
+    Custom(u16, String),
+}
+
+impl JoinError {
+    /// The code of the close frame
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::BadHandshake => 4000,
+            Self::LobbyNotFound => 4001,
+            Self::SessionNotFound => 4002,
+            Self::LobbyFull => 4003,
+            Self::GameInProgress => 4004,
+            Self::TooManyLobbies => 4005,
+            Self::Custom(code, _) if (4100..=4999).contains(code) => *code,
+            Self::Rejected(_) | Self::Custom(..) => 4010,
+        }
+    }
+    /// The reason in the close frame
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::BadHandshake => "bad handshake",
+            Self::LobbyNotFound => "lobby not found",
+            Self::SessionNotFound => "session not found",
+            Self::LobbyFull => "lobby full",
+            Self::GameInProgress => "game in progress",
+            Self::TooManyLobbies => "too many lobbies",
+            Self::Rejected(reason) | Self::Custom(_, reason) => reason,
+        }
+    }
+    pub(crate) fn close_frame(&self) -> CloseFrame<'static> {
+        // the reason can be at most 123 bytes long
+        let reason = self.reason();
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        CloseFrame {
+            code: CloseCode::from(self.code()),
+            reason: Cow::Owned(reason[..end].to_owned()),
+        }
+    }
+}
+
+impl Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+pub(crate) enum Handshake {
+    New(JoinRequest),
+    Join(LobbyId, JoinRequest),
+    Quickplay(JoinRequest),
+    Ranked(JoinRequest),
+    /// the session token, and whether the client wants a welcome message
+    Resume(String, bool),
+    List,
+}
+
+#[derive(Deserialize)]
+struct HandshakeMsg {
+    v: u32,
+    lobby: Option<String>,
+    resume: Option<String>,
+    #[serde(default)]
+    list: bool,
+    #[serde(flatten)]
+    request: JoinRequest,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LobbyList {
+    pub(crate) lobbies: Vec<PublicLobby>,
+}
+
+impl Handshake {
+    pub(crate) fn parse(msg: &str, config: &Config) -> Result<Self, JoinError> {
+        // a code that can't be parsed can't belong to any lobby
+        let lobby = |code: &str| {
+            config
+                .parse_lobby_code(code)
+                .ok_or(JoinError::LobbyNotFound)
+        };
+        if msg.trim_start().starts_with('{') {
+            let msg =
+                serde_json::from_str::<HandshakeMsg>(msg).map_err(|_| JoinError::BadHandshake)?;
+            if msg.v != PROTOCOL_VERSION {
+                return Err(JoinError::BadHandshake);
+            }
+            if msg.list {
+                return Ok(Self::List);
+            }
+            // only the matchmaking task may mark players as ranked, when it puts them into a lobby
+            let mut request = msg.request;
+            let ranked = std::mem::take(&mut request.ranked);
+            Ok(match (msg.resume, msg.lobby) {
+                (Some(token), _) => Self::Resume(token, request.welcome),
+                (None, Some(code)) => Self::Join(lobby(&code)?, request),
+                (None, None) if ranked => Self::Ranked(request),
+                (None, None) if request.quickplay => Self::Quickplay(request),
+                (None, None) => Self::New(request),
+            })
+        } else if msg == "new" {
+            Ok(Self::New(JoinRequest::default()))
+        } else if msg == "quickplay" {
+            Ok(Self::Quickplay(JoinRequest {
+                quickplay: true,
+                ..Default::default()
+            }))
+        } else if msg == "list" {
+            Ok(Self::List)
+        } else if let Some(token) = msg.strip_prefix("resume:") {
+            Ok(Self::Resume(token.to_owned(), false))
+        } else {
+            Ok(Self::Join(lobby(msg)?, JoinRequest::default()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(msg: &str) -> Result<Handshake, JoinError> {
+        Handshake::parse(msg, &Config::default())
+    }
+
+    #[test]
+    fn json_join() {
+        let msg = r#"{ "v": 1, "lobby": "kxqf", "name": "Mark", "password": "hunter2", "meta": { "color": "red" } }"#;
+        let Ok(Handshake::Join(id, request)) = parse(msg) else {
+            panic!("expected a join");
+        };
+        // the default alphabet is uppercase, so codes are too
+        assert_eq!(id.as_str(), "KXQF");
+        assert_eq!(request.name, "Mark");
+        assert_eq!(request.password.as_deref(), Some("hunter2"));
+        assert_eq!(request.meta["color"], "red");
+        assert!(!request.spectate);
+    }
+
+    #[test]
+    fn json_without_lobby() {
+        assert!(matches!(parse(r#"{ "v": 1 }"#), Ok(Handshake::New(_))));
+        assert!(matches!(
+            parse(r#"{ "v": 1, "quickplay": true, "mode": "duel" }"#),
+            Ok(Handshake::Quickplay(r)) if r.mode.as_deref() == Some("duel")
+        ));
+        assert!(matches!(
+            parse(r#"{ "v": 1, "ranked": true, "quickplay": true, "name": "Mark" }"#),
+            Ok(Handshake::Ranked(_))
+        ));
+        assert!(matches!(
+            parse(r#"{ "v": 1, "list": true }"#),
+            Ok(Handshake::List)
+        ));
+    }
+
+    #[test]
+    fn ranked_is_not_taken_from_the_client() {
+        let Ok(Handshake::Join(_, request)) =
+            parse(r#"{ "v": 1, "lobby": "KXQF", "ranked": true, "name": "Mark" }"#)
+        else {
+            panic!("expected a join");
+        };
+        assert!(!request.ranked);
+        assert!(matches!(
+            parse(r#"{ "v": 1, "ranked": true, "name": "Mark" }"#),
+            Ok(Handshake::Ranked(r)) if !r.ranked
+        ));
+    }
+
+    #[test]
+    fn resume_wins_over_lobby() {
+        assert!(matches!(
+            parse(r#"{ "v": 1, "resume": "abc", "lobby": "KXQF" }"#),
+            Ok(Handshake::Resume(token, false)) if token == "abc"
+        ));
+    }
+
+    #[test]
+    fn resume_welcome() {
+        assert!(matches!(
+            parse(r#"{ "v": 1, "resume": "abc", "welcome": true }"#),
+            Ok(Handshake::Resume(token, true)) if token == "abc"
+        ));
+    }
+
+    #[test]
+    fn bad_json() {
+        for msg in [
+            r#"{ "v": 2, "lobby": "KXQF" }"#,
+            r#"{ "lobby": "KXQF" }"#,
+            r#"{ "v": 1, "lobby": 5 }"#,
+            r#"{ "v": 1"#,
+        ] {
+            assert!(matches!(parse(msg), Err(JoinError::BadHandshake)), "{msg}");
+        }
+    }
+
+    #[test]
+    fn plain_text() {
+        assert!(matches!(parse("new"), Ok(Handshake::New(_))));
+        assert!(matches!(parse("quickplay"), Ok(Handshake::Quickplay(r)) if r.quickplay));
+        assert!(matches!(parse("list"), Ok(Handshake::List)));
+        assert!(
+            matches!(parse("resume:abc"), Ok(Handshake::Resume(token, false)) if token == "abc")
+        );
+        assert!(matches!(parse(" kxqf "), Ok(Handshake::Join(id, _)) if id.as_str() == "KXQF"));
+    }
+
+    #[test]
+    fn impossible_codes() {
+        for msg in ["", "ÄÖÜ", "THIS-CODE-IS-FAR-TOO-LONG"] {
+            assert!(matches!(parse(msg), Err(JoinError::LobbyNotFound)), "{msg}");
+        }
+        assert!(matches!(
+            parse(r#"{ "v": 1, "lobby": "" }"#),
+            Err(JoinError::LobbyNotFound)
+        ));
+    }
+}
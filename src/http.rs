@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        http::{header, HeaderMap, Method, Request, StatusCode},
+        protocol::Role,
+    },
+    WebSocketStream,
+};
+
+use crate::{connection::WebSocket, js, server::Config, site::Files, transport::Transport};
+
+/// requests with larger headers are rejected
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// The `Sec-WebSocket-Accept` header for the response,
+/// or `None` if this isn't a WebSocket handshake.
+pub(crate) fn websocket_accept_key(method: &Method, headers: &HeaderMap) -> Option<String> {
+    let contains = |name: header::HeaderName, value: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(value))
+    };
+    if method != Method::GET
+        || !contains(header::CONNECTION, "upgrade")
+        || !contains(header::UPGRADE, "websocket")
+        || headers.get(header::SEC_WEBSOCKET_VERSION)? != "13"
+    {
+        return None;
+    }
+    let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+    Some(derive_accept_key(key.as_bytes()))
+}
+
+/// Reads an HTTP request. If it is a WebSocket handshake, returns the WebSocket,
+/// otherwise responds with a file from `files` and closes the connection.
+pub(crate) async fn accept(
+    mut con: Box<dyn Transport>,
+    files: &dyn Files,
+    config: &Config,
+) -> Option<WebSocket> {
+    let (request, rest) = read_request(&mut con).await?;
+    if let Some(accept) = websocket_accept_key(request.method(), request.headers()) {
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+        );
+        con.write_all(response.as_bytes()).await.ok()?;
+        return Some(WebSocketStream::from_partially_read(con, rest, Role::Server, None).await);
+    }
+    let response = respond(&request, files, config).await;
+    _ = con.write_all(&response).await;
+    _ = con.shutdown().await;
+    None
+}
+
+/// the request's head, and the bytes that were read after it
+async fn read_request(con: &mut Box<dyn Transport>) -> Option<(Request<()>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.len() >= MAX_HEAD || con.read_buf(&mut buf).await.ok()? == 0 {
+            return None;
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(len) = parsed.parse(&buf).ok()? else {
+            continue;
+        };
+        let mut request = Request::builder().method(parsed.method?).uri(parsed.path?);
+        for h in parsed.headers.iter() {
+            request = request.header(h.name, h.value);
+        }
+        let request = request.body(()).ok()?;
+        return Some((request, buf.split_off(len)));
+    }
+}
+
+async fn respond(request: &Request<()>, files: &dyn Files, config: &Config) -> Vec<u8> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return response(StatusCode::METHOD_NOT_ALLOWED, "text/plain", b"");
+    }
+    // `Directory` checks the decoded path, so `%2e%2e` can't escape the directory
+    let Some(path) = percent_decode(request.uri().path()) else {
+        return response(StatusCode::BAD_REQUEST, "text/plain", b"bad path");
+    };
+    // the code ends up in the page, so it may only contain characters that lobby codes are made of
+    let lobby = path.strip_prefix("/join/").map(|code| {
+        config
+            .parse_lobby_code(code.trim_end_matches('/'))
+            .map(|id| id.to_string())
+            .filter(|code| {
+                code.bytes()
+                    .all(|c| config.lobby_code_alphabet.contains(&c))
+            })
+            .unwrap_or_default()
+    });
+    let mut path = match lobby {
+        Some(_) => "index.html".to_owned(),
+        None => path.trim_start_matches('/').to_owned(),
+    };
+    if path.is_empty() || path.ends_with('/') {
+        path.push_str("index.html");
+    }
+    let mut data = files.get(&path).await;
+    if data.is_none() && path == js::PATH.trim_start_matches('/') {
+        data = Some(Cow::Borrowed(js::CLIENT.as_bytes()));
+    }
+    // single-page apps handle their paths themselves
+    if data.is_none() && !path.rsplit('/').next().unwrap_or("").contains('.') {
+        path = "index.html".to_owned();
+        data = files.get(&path).await;
+    }
+    let Some(data) = data else {
+        return response(StatusCode::NOT_FOUND, "text/plain", b"not found");
+    };
+    let content_type = content_type(&path);
+    let data = if content_type.starts_with("text/html") {
+        Cow::Owned(
+            String::from_utf8_lossy(&data)
+                .replace("\\{websocket}", &websocket_url(request, config))
+                .replace("\\{lobby}", lobby.as_deref().unwrap_or(""))
+                .into_bytes(),
+        )
+    } else {
+        data
+    };
+    let mut response = response(StatusCode::OK, content_type, &data);
+    if request.method() == Method::HEAD {
+        response.truncate(response.len() - data.len());
+    }
+    response
+}
+
+/// decodes escapes like `%20` in a request's path.
+/// `None` if the result isn't valid UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&c, after)) = rest.split_first() {
+        let hex = after
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        match hex {
+            Some(hex) if c == b'%' => {
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &after[2..];
+            }
+            _ => {
+                decoded.push(c);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// the URL of the WebSocket on this server, as seen by the client
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn websocket_url(request: &Request<()>, config: &Config) -> String {
+    #[cfg(feature = "tls")]
+    let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "ws";
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .filter(|host| {
+            host.bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b".-:[]".contains(&c))
+        })
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}/")
+}
+
+fn response(status: StatusCode, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::percent_decode;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("/a%20b.html").as_deref(), Some("/a b.html"));
+        assert_eq!(percent_decode("/%2e%2E/x").as_deref(), Some("/../x"));
+        assert_eq!(percent_decode("/caf%C3%A9").as_deref(), Some("/café"));
+    }
+
+    #[test]
+    fn keeps_invalid_escapes() {
+        assert_eq!(percent_decode("/100%").as_deref(), Some("/100%"));
+        assert_eq!(percent_decode("/%zz%4").as_deref(), Some("/%zz%4"));
+        assert_eq!(percent_decode("/%+1").as_deref(), Some("/%+1"));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert_eq!(percent_decode("/%ff"), None);
+    }
+}
@@ -0,0 +1,40 @@
+//! The JavaScript client, so that browser games don't have to implement the protocol themselves.
+//!
+//! It does the handshake, reconnects using the session token, encodes and decodes JSON messages,
+//! matches requests with their responses (see `PlayerCon::reply`), measures the latency,
+//! and reads lobby codes from `/join/<code>` links.
+//!
+//! When the server serves a website (see `ServerBuilder::serve`), the client is available at `PATH`,
+//! unless the site has its own file there. Otherwise, serve `CLIENT` yourself, or put it into your page.
+//!
+//! ```html
+//! <script src="/ezbrowsergameserver.js"></script>
+//! <script>
+//!   const client = new EzClient();
+//!   client.onmessage = (msg) => console.log(msg);
+//!   client.join(EzClient.lobbyFromUrl(), { name: "Mark" }).then(({ lobby }) => console.log("joined", lobby));
+//! </script>
+//! ```
+//!
+//! The client sends `welcome: true` in its handshake, so the server sends
+//! `{ "ez": "welcome", "lobby": "KXQF", "token": "...", "spectator": false, "resumed": false }`
+//! before anything else once the player is in a lobby (`token` is `null` if the player can't reconnect).
+//! Text messages starting with `{"ez":"ping"` are latency measurements, which the server answers
+//! itself with the same message with `"ping"` replaced by `"pong"`. Your game never sees either of them.
+
+/// The version of the client, which is the version of this crate. Available as `EzClient.VERSION` in JavaScript.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Where the built-in HTTP server serves the client.
+pub const PATH: &str = "/ezbrowsergameserver.js";
+
+/// The client's source code. Defines the global `EzClient` class.
+pub const CLIENT: &str = concat!(
+    "// ezbrowsergameserver ",
+    env!("CARGO_PKG_VERSION"),
+    "\n(function (VERSION) {\n",
+    include_str!("client.js"),
+    "})(\"",
+    env!("CARGO_PKG_VERSION"),
+    "\");\n"
+);
@@ -1,132 +1,64 @@
-use std::{sync::Arc, time::Duration};
+use std::future::Future;
 
-use futures_util::TryStreamExt;
-use game::{LobbyState, PlayerCon, PlayerIndex};
-use tokio::{
-    net::{TcpListener, TcpStream, ToSocketAddrs},
-    sync::Mutex,
-};
-
-use crate::game::{InGame, Lobby};
+use game::LobbyState;
+use tokio::net::ToSocketAddrs;
 
+#[cfg(feature = "axum")]
+pub mod axum;
+mod connection;
 pub mod game;
+pub mod handshake;
+mod http;
+pub mod js;
+mod lobby_task;
+mod matchmaking;
+pub mod rating;
+pub mod server;
+pub mod site;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod transport;
+
+pub use server::{Connector, Server, ServerBuilder};
 
 pub mod prelude {
+    #[cfg(feature = "serde")]
+    pub use crate::game::{Codec, DecodeError, TypedLobbyState};
     pub use crate::{
-        game::{GameState, Lobby, LobbyState, PlayerIndex},
-        host,
+        game::{
+            GameState, LateJoin, Lobby, LobbyId, LobbyListing, LobbyState, PlayerId, PlayerIndex,
+            PublicLobby, Received, TimerId,
+        },
+        handshake::{JoinError, JoinRequest},
+        host, host_until,
+        server::Overflow,
+        Server,
     };
     pub use async_trait::async_trait;
 }
 
-/// Hosts the game's WebSocket.
+/// Hosts the game's WebSocket using the default configuration.
 ///
-/// this function ends in an infinite loop, so it never returns.
-/// to specify your `LobbyState` type, use the `host::<YourType>(addr).await` syntax.
-pub async fn host<S: LobbyState + 'static>(addr: impl ToSocketAddrs + Send + 'static) -> ! {
-    let lobbies: Arc<Mutex<Vec<Option<Lobby<S>>>>> = Default::default();
-    tokio::spawn(accept_new(addr, Arc::clone(&lobbies)));
-    loop {
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        let mut lock = lobbies.lock().await;
-        for (i, l) in lock.iter_mut().enumerate() {
-            if let Some(lobby) = l {
-                if lobby.players().is_empty() {
-                    *l = None;
-                } else {
-                    for index in lobby
-                        .players()
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(i, p)| if p.disconnected() { Some(i) } else { None })
-                        .collect::<Vec<_>>()
-                    {
-                        S::player_leaving(i, lobby, PlayerIndex(index)).await;
-                        lobby.players.remove(index);
-                    }
-                    if let Some(game_state) = S::lobby_update(i, lobby).await {
-                        let ig = InGame::new(l.take().unwrap(), game_state);
-                        tokio::spawn(in_game(ig, Arc::clone(&lobbies)));
-                    }
-                }
-            }
-        }
-    }
-}
-
-async fn in_game<S: LobbyState + 'static>(
-    mut in_game: InGame<S>,
-    lobbies: Arc<Mutex<Vec<Option<Lobby<S>>>>>,
-) {
-    loop {
-        tokio::time::sleep(Duration::from_millis(10)).await;
-        if in_game.update().await {
-            add_lobby(lobbies.lock().await.as_mut(), in_game.into_lobby());
-            return;
-        }
-        let indices = in_game
-            .lobby
-            .players()
-            .iter()
-            .enumerate()
-            .filter_map(|(i, p)| if p.disconnected() { Some(i) } else { None })
-            .collect::<Vec<_>>();
-        for index in indices.into_iter().rev() {
-            in_game.player_leaving(index).await;
-            in_game.lobby.players.remove(index);
-        }
-    }
-}
-
-async fn accept_new<S: LobbyState + 'static>(
-    addr: impl ToSocketAddrs + Send,
-    lobbies: Arc<Mutex<Vec<Option<Lobby<S>>>>>,
-) {
-    let server = TcpListener::bind(addr).await.unwrap();
-    loop {
-        match server.accept().await {
-            Ok((con, _)) => {
-                tokio::spawn(handle_new_connection(con, Arc::clone(&lobbies)));
-            }
-            _ => {}
-        }
-    }
-}
-
-fn add_lobby<S: LobbyState>(lobbies: &mut Vec<Option<Lobby<S>>>, lobby: Lobby<S>) -> usize {
-    if let Some(i) = lobbies.iter().position(|l| l.is_none()) {
-        lobbies[i] = Some(lobby);
-        i
-    } else {
-        lobbies.push(Some(lobby));
-        lobbies.len() - 1
-    }
+/// This only returns if the address can't be bound, otherwise it runs forever.
+/// To stop the server, use `host_until` instead.
+/// To specify your `LobbyState` type, use the `host::<YourType>(addr).await` syntax.
+/// To change the tick rate or other limits, use `Server::builder()`.
+pub async fn host<S: LobbyState + 'static>(
+    addr: impl ToSocketAddrs + Send + 'static,
+) -> std::io::Result<()> {
+    host_until::<S>(addr, std::future::pending()).await
 }
 
-async fn handle_new_connection<S: LobbyState>(
-    con: TcpStream,
-    lobbies: Arc<Mutex<Vec<Option<Lobby<S>>>>>,
-) {
-    if let Ok(mut con) = tokio_tungstenite::accept_async(con).await {
-        if let Ok(Some(msg)) = con.try_next().await {
-            if let Ok(lobby) = msg.into_text() {
-                let player = PlayerCon::new(S::new_player(), con);
-                if lobby == "new" {
-                    let l = Lobby::new(S::new(), vec![player]);
-                    let mut lobbies_lock = lobbies.lock().await;
-                    let lobby = add_lobby(lobbies_lock.as_mut(), l);
-                    S::player_joined(lobby, lobbies_lock[lobby].as_mut().unwrap(), PlayerIndex(0))
-                        .await;
-                } else if let Ok(lobby) = usize::from_str_radix(&lobby, 16) {
-                    let mut lobbies_lock = lobbies.lock().await;
-                    if let Some(Some(l)) = lobbies_lock.get_mut(lobby) {
-                        let pindex = l.players().len();
-                        l.join(player);
-                        S::player_joined(lobby, l, PlayerIndex(pindex)).await;
-                    }
-                }
-            }
-        }
-        // let mut con = PlayerCon::new(String::new(), con);
-    }
+/// Like `host`, but shuts the server down once `shutdown` completes.
+/// See `Server::run_until`.
+pub async fn host_until<S: LobbyState + 'static>(
+    addr: impl ToSocketAddrs + Send + 'static,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    Server::<S>::builder()
+        .bind(addr)
+        .await?
+        .run_until(shutdown)
+        .await;
+    Ok(())
 }
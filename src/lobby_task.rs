@@ -0,0 +1,620 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::{
+    sync::{mpsc, watch},
+    time::Interval,
+};
+
+use crate::{
+    connection::Connection,
+    game::{
+        resumed_close_frame, shutdown_close_frame, GameState, LateJoin, Lobby, LobbyId, LobbyState,
+        PlayerCon, PlayerId, PlayerIndex, PublicLobby, Received,
+    },
+    handshake::JoinError,
+    server::{interval, wait_for_shutdown, Shared},
+};
+
+/// Drives one lobby, and the games it plays, until it is empty or the server shuts down.
+pub(crate) struct LobbyTask<S: LobbyState> {
+    id: LobbyId,
+    lobby: Lobby<S>,
+    joins: mpsc::UnboundedReceiver<Join<S::PlayerState>>,
+    /// this lobby's entry in the public lobby list
+    listing: watch::Sender<Option<PublicLobby>>,
+    /// players sent to `joins` whose join isn't reflected in `listing` yet
+    joining: Arc<AtomicUsize>,
+    /// joins handled since `listing` was last updated
+    joined: usize,
+    shared: Arc<Shared<S>>,
+    stopped: watch::Receiver<bool>,
+}
+
+/// sent to a lobby's task to add a connection to it
+pub(crate) enum Join<D> {
+    New(Box<PlayerCon<D>>),
+    /// a connection which wants to take over the disconnected player with this session token,
+    /// and whether it wants a welcome message
+    Resume(String, bool, Connection),
+}
+
+enum Event<D> {
+    Shutdown,
+    Join(Join<D>),
+    /// a player's reader task received something
+    Messages,
+    Timers,
+    Tick,
+}
+
+impl<S: LobbyState + 'static> LobbyTask<S> {
+    pub(crate) fn new(
+        id: LobbyId,
+        lobby: Lobby<S>,
+        joins: mpsc::UnboundedReceiver<Join<S::PlayerState>>,
+        listing: watch::Sender<Option<PublicLobby>>,
+        joining: Arc<AtomicUsize>,
+        shared: Arc<Shared<S>>,
+    ) -> Self {
+        let stopped = shared.stopped.clone();
+        Self {
+            id,
+            lobby,
+            joins,
+            listing,
+            joining,
+            joined: 0,
+            shared,
+            stopped,
+        }
+    }
+
+    pub(crate) async fn run(mut self) {
+        let id = self.id;
+        let mut tick = interval(self.shared.config.lobby_tick);
+        let mut last_tick = Instant::now();
+        loop {
+            match self.next_event(&mut tick).await {
+                Event::Shutdown => break,
+                Event::Join(Join::New(mut player)) => {
+                    self.joined += 1;
+                    let spectate = player.join_request().spectate;
+                    let accepted = if !spectate && self.lobby.is_full() {
+                        Err(JoinError::LobbyFull)
+                    } else {
+                        S::accept_join(id, &mut self.lobby, player.join_request()).await
+                    };
+                    if accepted.is_ok() {
+                        self.register_session(player.session_token()).await;
+                        self.welcome(&player, spectate, false);
+                    }
+                    match accepted {
+                        Ok(()) if spectate => self.join_spectator(*player).await,
+                        Ok(()) => {
+                            let index = PlayerIndex(self.lobby.players.len());
+                            self.lobby.join(*player);
+                            S::player_joined(id, &mut self.lobby, index).await;
+                        }
+                        Err(error) => player.reject(&error).await,
+                    }
+                }
+                Event::Join(Join::Resume(token, welcome, con)) => {
+                    if let Some(player) = self.resume(&token, welcome, con).await {
+                        S::player_reconnected(id, &mut self.lobby, player).await;
+                    }
+                }
+                Event::Messages => {
+                    for player in self.lobby.player_ids().collect::<Vec<_>>() {
+                        while let Some((index, msg)) = self.player_message(player).await {
+                            match msg {
+                                Received::Text(msg) => {
+                                    S::on_message(id, &mut self.lobby, index, msg).await
+                                }
+                                Received::Binary(msg) => {
+                                    S::on_binary(id, &mut self.lobby, index, msg).await
+                                }
+                            }
+                        }
+                    }
+                    self.spectator_messages().await;
+                }
+                Event::Timers => {
+                    for timer in self.lobby.expired_timers() {
+                        S::on_timer(id, &mut self.lobby, timer).await;
+                    }
+                }
+                Event::Tick => {
+                    let now = Instant::now();
+                    S::on_tick(id, &mut self.lobby, now - last_tick).await;
+                    last_tick = now;
+                    if let Some(game_state) = S::lobby_update(id, &mut self.lobby).await {
+                        if self.run_game(game_state).await {
+                            break;
+                        }
+                        if !self.lobby.queued.is_empty() {
+                            for player in std::mem::take(&mut self.lobby.queued) {
+                                if player.disconnected() {
+                                    self.unregister_session(player.session_token()).await;
+                                    continue;
+                                }
+                                let index = PlayerIndex(self.lobby.players.len());
+                                self.lobby.join(player);
+                                S::player_joined(id, &mut self.lobby, index).await;
+                            }
+                            // they may have sent messages while waiting
+                            self.lobby.events.notify_one();
+                        }
+                        tick.reset();
+                        last_tick = Instant::now();
+                    }
+                }
+            }
+            self.handle_disconnects(None).await;
+            self.update_listing();
+            if self.lobby.players.is_empty() && self.lobby.spectators.is_empty() {
+                // new players are only sent to `joins` while the registry is locked,
+                // so if there are none now, nobody can join this lobby anymore.
+                let mut registry = self.shared.registry.lock().await;
+                if self.joins.is_empty() {
+                    registry.remove_lobby(id);
+                    return;
+                }
+            }
+        }
+        // the server is shutting down
+        S::server_shutdown(id, &mut self.lobby).await;
+        self.lobby.close_all().await;
+        let mut registry = self.shared.registry.lock().await;
+        registry.remove_lobby(id);
+        while let Ok(join) = self.joins.try_recv() {
+            match join {
+                Join::New(mut player) => player.close_shutdown().await,
+                Join::Resume(_, _, mut con) => con.close(Some(shutdown_close_frame())).await,
+            }
+        }
+    }
+
+    /// Runs a game until it ends. Returns true if the server is shutting down.
+    async fn run_game(&mut self, mut game_state: Box<dyn GameState<S>>) -> bool {
+        self.lobby.in_game = true;
+        let shutdown = self.game_loop(game_state.as_mut()).await;
+        self.lobby.in_game = false;
+        if shutdown {
+            game_state.server_shutdown(&mut self.lobby).await;
+        }
+        shutdown
+    }
+
+    async fn game_loop(&mut self, game_state: &mut dyn GameState<S>) -> bool {
+        let mut tick = interval(self.shared.config.game_tick);
+        let mut last_tick = Instant::now();
+        loop {
+            match self.next_event(&mut tick).await {
+                Event::Shutdown => return true,
+                Event::Join(Join::New(mut player)) => {
+                    self.joined += 1;
+                    let late_join = if player.join_request().spectate {
+                        LateJoin::Spectate
+                    } else {
+                        game_state.late_join()
+                    };
+                    let accepted = match late_join {
+                        LateJoin::Reject => Err(JoinError::GameInProgress),
+                        LateJoin::Queue | LateJoin::Join if self.lobby.is_full() => {
+                            Err(JoinError::LobbyFull)
+                        }
+                        _ => S::accept_join(self.id, &mut self.lobby, player.join_request()).await,
+                    };
+                    if accepted.is_ok() {
+                        let spectate = matches!(late_join, LateJoin::Spectate);
+                        self.register_session(player.session_token()).await;
+                        self.welcome(&player, spectate, false);
+                    }
+                    match (accepted, late_join) {
+                        (Err(error), _) => player.reject(&error).await,
+                        (Ok(()), LateJoin::Queue) => self.lobby.queued.push(*player),
+                        (Ok(()), LateJoin::Spectate) => self.join_spectator(*player).await,
+                        (Ok(()), _) => {
+                            let index = PlayerIndex(self.lobby.players.len());
+                            self.lobby.join(*player);
+                            game_state.player_joined(&mut self.lobby, index).await;
+                        }
+                    }
+                }
+                Event::Join(Join::Resume(token, welcome, con)) => {
+                    if let Some(player) = self.resume(&token, welcome, con).await {
+                        game_state.player_reconnected(&mut self.lobby, player).await;
+                    }
+                }
+                Event::Messages => {
+                    for player in self.lobby.player_ids().collect::<Vec<_>>() {
+                        while let Some((index, msg)) = self.player_message(player).await {
+                            match msg {
+                                Received::Text(msg) => {
+                                    game_state.on_message(&mut self.lobby, index, msg).await
+                                }
+                                Received::Binary(msg) => {
+                                    game_state.on_binary(&mut self.lobby, index, msg).await
+                                }
+                            }
+                        }
+                    }
+                    self.spectator_messages().await;
+                }
+                Event::Timers => {
+                    for timer in self.lobby.expired_timers() {
+                        game_state.on_timer(&mut self.lobby, timer).await;
+                    }
+                }
+                Event::Tick => {
+                    let now = Instant::now();
+                    game_state.on_tick(&mut self.lobby, now - last_tick).await;
+                    last_tick = now;
+                    if game_state.update(&mut self.lobby).await {
+                        self.lobby.reset = true;
+                        return false;
+                    }
+                }
+            }
+            self.handle_disconnects(Some(&mut *game_state)).await;
+            self.update_listing();
+        }
+    }
+
+    /// updates this lobby's entry in the public lobby list, if it changed
+    fn update_listing(&mut self) {
+        let listing = self.lobby.state.listing().map(|listing| PublicLobby {
+            code: self.id,
+            listing,
+            players: self.lobby.players.len(),
+            max_players: self.lobby.max_players,
+            spectators: self.lobby.spectators.len(),
+            in_game: self.lobby.in_game,
+        });
+        self.listing.send_if_modified(|current| {
+            if *current != listing {
+                *current = listing;
+                true
+            } else {
+                false
+            }
+        });
+        // only now that the players who joined are counted in `listing`
+        self.joining.fetch_sub(self.joined, Ordering::Release);
+        self.joined = 0;
+    }
+
+    /// sends the welcome message, if the client wants one, see `PlayerCon::welcome`
+    fn welcome(&self, player: &PlayerCon<S::PlayerState>, spectator: bool, resumed: bool) {
+        let resumable = self.shared.config.reconnect_grace.is_some();
+        player.welcome(self.id, spectator, resumable, resumed);
+    }
+
+    /// Lets a client resume this player's session, see `ServerBuilder::reconnect_grace`.
+    /// This happens as soon as they join, not when they disconnect,
+    /// so that a client can resume before the server notices that its old connection is gone.
+    async fn register_session(&mut self, token: &str) {
+        if self.shared.config.reconnect_grace.is_some() {
+            let mut registry = self.shared.registry.lock().await;
+            registry.sessions.insert(token.to_owned(), self.id);
+        }
+    }
+
+    /// called when a player or spectator leaves the lobby for good
+    async fn unregister_session(&mut self, token: &str) {
+        if self.shared.config.reconnect_grace.is_some() {
+            self.shared.registry.lock().await.sessions.remove(token);
+        }
+    }
+
+    /// adds a player as a spectator, giving them a new `SpectatorState`
+    async fn join_spectator(&mut self, player: PlayerCon<S::PlayerState>) {
+        let spectator = player.with_data(Default::default());
+        let spectator_id = spectator.id();
+        self.lobby.join_spectator(spectator);
+        S::spectator_joined(self.id, &mut self.lobby, spectator_id).await;
+    }
+
+    /// The next new message from this player, and their current index.
+    /// The hooks can demote players, which moves the others, so this looks them up by id every time.
+    async fn player_message(&mut self, player: PlayerId) -> Option<(PlayerIndex, Received)> {
+        let index = self.lobby.player_index(player)?;
+        let msg = self.lobby.players[index.i()].recv_new().await?;
+        Some((index, msg))
+    }
+
+    /// Passes the spectators' new messages to `spectator_message` and `spectator_binary`.
+    async fn spectator_messages(&mut self) {
+        // the hooks can turn spectators into players, so look them up by id every time
+        let spectators: Vec<PlayerId> = self.lobby.spectators.iter().map(|s| s.id()).collect();
+        for spectator in spectators {
+            while let Some(con) = self.lobby.spectator(spectator) {
+                let Some(msg) = con.recv_new().await else {
+                    break;
+                };
+                match msg {
+                    Received::Text(msg) => {
+                        S::spectator_message(self.id, &mut self.lobby, spectator, msg).await
+                    }
+                    Received::Binary(msg) => {
+                        S::spectator_binary(self.id, &mut self.lobby, spectator, msg).await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes disconnected players, or, if reconnecting is enabled,
+    /// keeps them until their grace period is over.
+    /// Calls the hooks on `game_state` if there is a game, or on `S` otherwise.
+    async fn handle_disconnects(&mut self, mut game_state: Option<&mut dyn GameState<S>>) {
+        let id = self.id;
+        // the hooks can demote players, so look them up by id every time
+        for player_id in self.lobby.disconnected_players() {
+            let Some(index) = self.lobby.player_index(player_id) else {
+                continue;
+            };
+            let player = &mut self.lobby.players[index.i()];
+            if let Some(grace) = self.shared.config.reconnect_grace {
+                match player.disconnected_since {
+                    None => {
+                        player.disconnected_since = Some(Instant::now());
+                        match game_state.as_mut() {
+                            Some(game_state) => {
+                                game_state
+                                    .player_disconnected(&mut self.lobby, player_id)
+                                    .await
+                            }
+                            None => S::player_disconnected(id, &mut self.lobby, player_id).await,
+                        }
+                        continue;
+                    }
+                    Some(since) if since.elapsed() < grace => continue,
+                    Some(_) => {}
+                }
+            }
+            match game_state.as_mut() {
+                Some(game_state) => game_state.player_leaving(&mut self.lobby, player_id).await,
+                None => S::player_leaving(id, &mut self.lobby, player_id).await,
+            }
+            if let Some(index) = self.lobby.player_index(player_id) {
+                let player = self.lobby.players.remove(index.i());
+                self.unregister_session(player.session_token()).await;
+            }
+        }
+        let spectators: Vec<PlayerId> = self
+            .lobby
+            .spectators
+            .iter()
+            .filter(|s| s.disconnected())
+            .map(|s| s.id())
+            .collect();
+        // `spectator_leaving` can promote the others
+        for spectator_id in spectators {
+            S::spectator_leaving(id, &mut self.lobby, spectator_id).await;
+            let spectators = &self.lobby.spectators;
+            let Some(index) = spectators.iter().position(|s| s.id() == spectator_id) else {
+                continue;
+            };
+            let spectator = self.lobby.spectators.remove(index);
+            self.unregister_session(spectator.session_token()).await;
+        }
+    }
+
+    /// Gives the player with this session token a new connection.
+    /// If the server hasn't noticed that their old connection is gone yet, it is closed now.
+    /// Returns `None` if there is no such player, for example because their grace period is over.
+    async fn resume(
+        &mut self,
+        token: &str,
+        welcome: bool,
+        mut con: Connection,
+    ) -> Option<PlayerId> {
+        let Some(index) = self.lobby.find_session(token) else {
+            con.close(Some(JoinError::SessionNotFound.close_frame()))
+                .await;
+            return None;
+        };
+        let mut old = self.lobby.players[index].reconnect(con, welcome);
+        // closing waits for the old client, which may be gone without the server knowing
+        tokio::spawn(async move { old.close(Some(resumed_close_frame())).await });
+        self.welcome(&self.lobby.players[index], false, true);
+        Some(self.lobby.players[index].id())
+    }
+
+    async fn next_event(&mut self, tick: &mut Interval) -> Event<S::PlayerState> {
+        let next_timer = self.lobby.next_timer();
+        tokio::select! {
+            biased;
+            _ = wait_for_shutdown(&mut self.stopped) => Event::Shutdown,
+            Some(join) = self.joins.recv() => Event::Join(join),
+            _ = self.lobby.events.notified() => Event::Messages,
+            _ = tokio::time::sleep_until(next_timer.unwrap_or_else(Instant::now).into()), if next_timer.is_some() => Event::Timers,
+            _ = tick.tick() => Event::Tick,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::DuplexStream;
+    use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
+
+    use crate::{prelude::*, rating::RankedMatchmaking, Connector, ServerBuilder};
+
+    /// Sends every player the lobby code when they join and echoes their messages.
+    /// `spectate` demotes the sender, and a player leaving demotes the first player.
+    /// Players who reconnect are sent `reconnected`.
+    struct TestLobby;
+
+    #[async_trait]
+    impl LobbyState for TestLobby {
+        type PlayerState = ();
+        type SpectatorState = ();
+        fn new() -> Self {
+            Self
+        }
+        fn new_player() {}
+        async fn player_joined(id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex) {
+            lobby.get_player(player).send(id.to_string()).await;
+        }
+        async fn player_leaving(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerId) {
+            let first = lobby.player_ids().next().filter(|first| *first != player);
+            if let Some(first) = first {
+                lobby.demote_player(first);
+            }
+        }
+        async fn player_reconnected(_id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerId) {
+            if let Some(player) = lobby.player(player) {
+                player.send("reconnected".to_owned()).await;
+            }
+        }
+        async fn lobby_update(
+            _id: LobbyId,
+            _lobby: &mut Lobby<Self>,
+        ) -> Option<Box<dyn GameState<Self>>> {
+            None
+        }
+        async fn on_message(
+            _id: LobbyId,
+            lobby: &mut Lobby<Self>,
+            player: PlayerIndex,
+            msg: String,
+        ) {
+            let player = lobby.get_player(player);
+            player.send(msg.clone()).await;
+            if msg == "spectate" {
+                let id = player.id();
+                lobby.demote_player(id);
+            }
+        }
+    }
+
+    type Client = WebSocketStream<DuplexStream>;
+
+    async fn server(builder: ServerBuilder<TestLobby>) -> Connector {
+        let server = builder.build().await.unwrap();
+        let connector = server.connector();
+        tokio::spawn(server.run());
+        connector
+    }
+
+    /// connects, sends `handshake` and returns the first message,
+    /// which is the lobby code unless the handshake asked for a welcome message
+    async fn join(connector: &Connector, handshake: &str) -> (Client, String) {
+        let (client, server) = tokio::io::duplex(4096);
+        connector.connect(server);
+        let (mut client, _) = client_async("ws://localhost/", client).await.unwrap();
+        send(&mut client, handshake).await;
+        let first = recv(&mut client).await;
+        (client, first)
+    }
+
+    async fn send(client: &mut Client, msg: &str) {
+        client.send(Message::Text(msg.to_owned())).await.unwrap();
+    }
+
+    /// the next text message, skipping the server's pings
+    async fn recv(client: &mut Client) -> String {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no message from the server");
+            match msg {
+                Some(Ok(Message::Text(msg))) => return msg,
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                other => panic!("expected a text message, got {other:?}"),
+            }
+        }
+    }
+
+    /// the close code of a client the server rejected
+    async fn rejected(connector: &Connector, handshake: &str) -> u16 {
+        let (client, server) = tokio::io::duplex(4096);
+        connector.connect(server);
+        let (mut client, _) = client_async("ws://localhost/", client).await.unwrap();
+        send(&mut client, handshake).await;
+        match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => frame.code.into(),
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ranked_names_are_unique() {
+        let builder = Server::builder().ranked_matchmaking(RankedMatchmaking::default());
+        let connector = server(builder).await;
+        let mark = r#"{ "v": 1, "ranked": true, "name": "Mark" }"#;
+        let (client, server) = tokio::io::duplex(4096);
+        connector.connect(server);
+        let (mut first, _) = client_async("ws://localhost/", client).await.unwrap();
+        send(&mut first, mark).await;
+        // otherwise, the two Marks would be matched with each other
+        assert_eq!(rejected(&connector, mark).await, 4010);
+        let (_anna, code) = join(&connector, r#"{ "v": 1, "ranked": true, "name": "Anna" }"#).await;
+        assert_eq!(recv(&mut first).await, code);
+        // the name stays taken while its player is in a ranked lobby
+        assert_eq!(rejected(&connector, mark).await, 4010);
+    }
+
+    #[tokio::test]
+    async fn on_message_can_demote_players() {
+        let connector = server(Server::builder()).await;
+        let (mut first, code) = join(&connector, "new").await;
+        let (mut second, _) = join(&connector, &code).await;
+        send(&mut first, "spectate").await;
+        assert_eq!(recv(&mut first).await, "spectate");
+        // the lobby's task is still running and has the second player at index 0 now
+        send(&mut second, "hi").await;
+        assert_eq!(recv(&mut second).await, "hi");
+    }
+
+    #[tokio::test]
+    async fn player_leaving_can_demote_players() {
+        let connector = server(Server::builder()).await;
+        let (_first, code) = join(&connector, "new").await;
+        let (leaving, _) = join(&connector, &code).await;
+        let (mut last, _) = join(&connector, &code).await;
+        drop(leaving);
+        // `first` is demoted, which moves `last` to index 1, where `leaving` was
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        send(&mut last, "hi").await;
+        assert_eq!(recv(&mut last).await, "hi");
+    }
+
+    #[tokio::test]
+    async fn resume_takes_over_a_connection_which_looks_alive() {
+        let grace = Some(Duration::from_secs(60));
+        // without pings, the old client has nothing to answer once the server closed its connection
+        let builder = Server::builder().reconnect_grace(grace).ping_interval(None);
+        let connector = server(builder).await;
+        let (mut old, welcome) = join(&connector, r#"{ "v": 1, "welcome": true }"#).await;
+        let welcome: serde_json::Value = serde_json::from_str(&welcome).unwrap();
+        let token = welcome["token"].as_str().unwrap();
+        // the server still thinks that `old` is connected
+        let resume = format!(r#"{{ "v": 1, "resume": "{token}", "welcome": true }}"#);
+        let (mut new, welcome) = join(&connector, &resume).await;
+        let welcome: serde_json::Value = serde_json::from_str(&welcome).unwrap();
+        assert_eq!(welcome["resumed"], true);
+        assert_eq!(recv(&mut new).await, "reconnected");
+        let close = loop {
+            match old.next().await {
+                Some(Ok(Message::Close(frame))) => break frame,
+                Some(Ok(_)) => {}
+                other => panic!("expected a close frame, got {other:?}"),
+            }
+        };
+        assert_eq!(u16::from(close.unwrap().code), 4006);
+        send(&mut new, "hi").await;
+        assert_eq!(recv(&mut new).await, "hi");
+    }
+}
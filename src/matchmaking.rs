@@ -0,0 +1,240 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, Notify};
+
+use crate::{
+    game::{LobbyState, PlayerCon},
+    rating::{RankedMatchmaking, Rating},
+    server::{interval, wait_for_shutdown, Shared},
+};
+
+/// The queue of players waiting for ranked matchmaking, see `ServerBuilder::ranked_matchmaking`.
+pub(crate) struct Matchmaking<S: LobbyState> {
+    pub(crate) queue: mpsc::UnboundedSender<(PlayerCon<S::PlayerState>, Rating, RankedName)>,
+    /// passed to the reader tasks of waiting players
+    pub(crate) events: Arc<Notify>,
+    /// names of the players who are waiting or in a ranked lobby
+    names: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<S: LobbyState> Matchmaking<S> {
+    pub(crate) fn new(
+        queue: mpsc::UnboundedSender<(PlayerCon<S::PlayerState>, Rating, RankedName)>,
+    ) -> Self {
+        Self {
+            queue,
+            events: Arc::new(Notify::new()),
+            names: Default::default(),
+        }
+    }
+    /// reserves `name` for one ranked player, or returns None if another one already has it
+    pub(crate) fn claim(&self, name: &str) -> Option<RankedName> {
+        let mut names = self.names.lock().unwrap();
+        names.insert(name.to_owned()).then(|| RankedName {
+            name: name.to_owned(),
+            names: Arc::clone(&self.names),
+        })
+    }
+}
+
+/// A name which is in use by a ranked player, so that nobody can be in two ranked games at once.
+/// The name is released when this is dropped, together with the player.
+pub(crate) struct RankedName {
+    name: String,
+    names: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for RankedName {
+    fn drop(&mut self) {
+        self.names.lock().unwrap().remove(&self.name);
+    }
+}
+
+struct Waiting<D> {
+    player: PlayerCon<D>,
+    rating: f64,
+    since: Instant,
+}
+
+/// Groups waiting players by rating and sends each group to a new lobby, until the server shuts down.
+pub(crate) async fn run<S: LobbyState + 'static>(
+    settings: RankedMatchmaking,
+    mut queue: mpsc::UnboundedReceiver<(PlayerCon<S::PlayerState>, Rating, RankedName)>,
+    events: Arc<Notify>,
+    shared: Arc<Shared<S>>,
+) {
+    let mut stopped = shared.stopped.clone();
+    let mut waiting = Vec::<Waiting<S::PlayerState>>::new();
+    // the windows grow over time, so groups have to be looked for even if nobody new arrives
+    let mut tick = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            biased;
+            _ = wait_for_shutdown(&mut stopped) => break,
+            Some((mut player, rating, name)) = queue.recv() => {
+                player.set_ranked(name);
+                waiting.push(Waiting {
+                    player,
+                    rating: rating.value,
+                    since: Instant::now(),
+                });
+            }
+            // a waiting player left (or sent something, which their lobby will receive later)
+            _ = events.notified() => {}
+            _ = tick.tick() => {}
+        }
+        waiting.retain(|w| !w.player.disconnected());
+        while let Some(group) = find_group(&mut waiting, &settings) {
+            let mut registry = shared.registry.lock().await;
+            match registry.create_lobby(&shared, group[0].player.join_request()) {
+                Some(lobby) => {
+                    for w in group {
+                        lobby.join_player(w.player);
+                    }
+                }
+                None => {
+                    // too many lobbies, try again later
+                    waiting.extend(group);
+                    waiting.sort_by_key(|w| w.since);
+                    break;
+                }
+            }
+        }
+    }
+    for mut w in waiting {
+        w.player.close_shutdown().await;
+    }
+}
+
+/// Removes a group of players whose ratings are close enough from `waiting` (which is ordered by `since`).
+fn find_group<D>(
+    waiting: &mut Vec<Waiting<D>>,
+    settings: &RankedMatchmaking,
+) -> Option<Vec<Waiting<D>>> {
+    let now = Instant::now();
+    let players = waiting
+        .iter()
+        .map(|w| (w.rating, now - w.since))
+        .collect::<Vec<_>>();
+    let group = pick_group(&players, settings)?;
+    // back to front, so that the other indices stay valid
+    let mut group = group
+        .into_iter()
+        .rev()
+        .map(|j| waiting.remove(j))
+        .collect::<Vec<_>>();
+    group.reverse();
+    Some(group)
+}
+
+/// The indices (in ascending order) of a group of players whose ratings are close enough,
+/// given every waiting player's rating and how long they have waited, longest first.
+/// Players who waited longer, and so have a wider window, are matched first,
+/// with the players whose ratings are closest to theirs.
+fn pick_group(players: &[(f64, Duration)], settings: &RankedMatchmaking) -> Option<Vec<usize>> {
+    if players.len() < settings.players {
+        return None;
+    }
+    for (i, &(rating, waited)) in players.iter().enumerate() {
+        let window = settings.window(waited);
+        let distance = |j: usize| (players[j].0 - rating).abs();
+        let mut close = (0..players.len())
+            .filter(|&j| j != i && distance(j) <= window)
+            .collect::<Vec<_>>();
+        if close.len() + 1 < settings.players {
+            continue;
+        }
+        close.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+        close.truncate(settings.players - 1);
+        close.push(i);
+        close.sort_unstable();
+        return Some(close);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn needs_enough_players() {
+        let settings = RankedMatchmaking::default();
+        assert_eq!(pick_group(&[], &settings), None);
+        assert_eq!(pick_group(&[(1500.0, secs(60))], &settings), None);
+    }
+
+    #[test]
+    fn groups_close_ratings() {
+        let settings = RankedMatchmaking::default();
+        let players = [(1500.0, secs(0)), (1600.0, secs(0))];
+        assert_eq!(pick_group(&players, &settings), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn window_widens_while_waiting() {
+        let settings = RankedMatchmaking::default();
+        // 200 apart, but the window starts at 100 and grows by 10 per second
+        assert_eq!(
+            pick_group(&[(1500.0, secs(5)), (1700.0, secs(0))], &settings),
+            None
+        );
+        assert_eq!(
+            pick_group(&[(1500.0, secs(10)), (1700.0, secs(0))], &settings),
+            Some(vec![0, 1])
+        );
+    }
+
+    #[test]
+    fn window_is_capped() {
+        let settings = RankedMatchmaking {
+            max_window: Some(150.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            pick_group(&[(1500.0, secs(3600)), (1700.0, secs(0))], &settings),
+            None
+        );
+    }
+
+    #[test]
+    fn longest_waiting_gets_closest_players() {
+        let settings = RankedMatchmaking {
+            players: 3,
+            ..Default::default()
+        };
+        let players = [
+            (1500.0, secs(10)),
+            (1580.0, secs(5)),
+            (1490.0, secs(3)),
+            (1460.0, secs(1)),
+            (1900.0, secs(0)),
+        ];
+        assert_eq!(pick_group(&players, &settings), Some(vec![0, 2, 3]));
+    }
+
+    #[test]
+    fn skips_players_nobody_fits() {
+        let settings = RankedMatchmaking::default();
+        // the first player waited longest, but nobody is close to them yet
+        let players = [(2500.0, secs(10)), (1500.0, secs(5)), (1550.0, secs(0))];
+        assert_eq!(pick_group(&players, &settings), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn single_player_lobbies() {
+        let settings = RankedMatchmaking {
+            players: 1,
+            ..Default::default()
+        };
+        assert_eq!(pick_group(&[(1500.0, secs(0))], &settings), Some(vec![0]));
+    }
+}
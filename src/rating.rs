@@ -0,0 +1,318 @@
+//! Player ratings for competitive games.
+//!
+//! Games report the outcome of a match using `lobby.report_result()`, which updates
+//! every player's `Rating` in the server's `RatingStore` (see `ServerBuilder::ratings`).
+//! Ratings belong to a player's name, so players keep them when they join a different lobby.
+//!
+//! With `ServerBuilder::ranked_matchmaking`, clients can send `{ "v": 1, "ranked": true, "name": "Mark" }`
+//! to wait for players with a similar rating. Once there are enough, they all join a new lobby together.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// A player's Elo rating.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Rating {
+    pub value: f64,
+    /// How many rated games the player has played.
+    pub games: u32,
+}
+
+impl Rating {
+    /// The rating of a player who hasn't played any rated games yet.
+    pub const INITIAL: Self = Self {
+        value: 1500.0,
+        games: 0,
+    };
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self::INITIAL
+    }
+}
+
+/// Calculates everyone's new rating after a match.
+///
+/// `players` are the players' ratings and their place, where 0 is the winner and equal places are draws.
+/// Every pair of players is treated as one game between the two of them,
+/// and the changes are scaled so that a match counts as one game for every player.
+/// New players' ratings change faster during their first 10 games.
+pub fn elo(players: &[(Rating, u32)]) -> Vec<Rating> {
+    let opponents = players.len().saturating_sub(1).max(1) as f64;
+    players
+        .iter()
+        .map(|(rating, place)| {
+            let k = if rating.games < 10 { 40.0 } else { 20.0 };
+            let change = players
+                .iter()
+                .map(|(other, other_place)| {
+                    let expected = 1.0 / (1.0 + 10f64.powf((other.value - rating.value) / 400.0));
+                    let actual = match place.cmp(other_place) {
+                        std::cmp::Ordering::Less => 1.0,
+                        std::cmp::Ordering::Equal => 0.5,
+                        std::cmp::Ordering::Greater => 0.0,
+                    };
+                    actual - expected
+                })
+                .sum::<f64>();
+            // comparing a player with themselves adds 0.5 - 0.5 = 0
+            Rating {
+                value: rating.value + k * change / opponents,
+                games: rating.games + 1,
+            }
+        })
+        .collect()
+}
+
+/// Calculates new ratings from the old ones, see `RatingStore::update`.
+pub type RatingUpdate<'a> = &'a (dyn Fn(&[Rating]) -> Vec<Rating> + Send + Sync);
+
+/// Where ratings are kept, see `ServerBuilder::ratings`.
+#[async_trait]
+pub trait RatingStore: Send + Sync + 'static {
+    /// The player's rating, or `Rating::INITIAL` if they don't have one yet.
+    async fn get(&self, name: &str) -> Rating;
+    /// Calls `update` with the ratings of `names` (in the same order), stores the ratings it returns,
+    /// and returns them too. `lobby.report_result()` never passes the same name twice.
+    ///
+    /// Nothing else may change these ratings in between, so when two lobbies report results
+    /// for the same player at the same time, neither update is lost.
+    async fn update(&self, names: &[&str], update: RatingUpdate<'_>) -> Vec<Rating>;
+}
+
+/// true if a name appears more than once
+pub(crate) fn has_duplicates(names: &[&str]) -> bool {
+    let mut seen = HashSet::new();
+    !names.iter().all(|name| seen.insert(*name))
+}
+
+/// `RatingStore::update` for ratings in a `HashMap`.
+/// If a name appears twice, nothing is stored and the old ratings are returned.
+fn update_map(
+    ratings: &mut HashMap<String, Rating>,
+    names: &[&str],
+    update: RatingUpdate<'_>,
+) -> Vec<Rating> {
+    let old = names
+        .iter()
+        .map(|name| ratings.get(*name).copied().unwrap_or_default())
+        .collect::<Vec<_>>();
+    if has_duplicates(names) {
+        return old;
+    }
+    let new = update(&old);
+    for (name, rating) in names.iter().zip(&new) {
+        ratings.insert((*name).to_owned(), *rating);
+    }
+    new
+}
+
+/// Keeps ratings in memory, so they are lost when the server stops.
+#[derive(Default)]
+pub struct MemoryRatings {
+    ratings: Mutex<HashMap<String, Rating>>,
+}
+
+#[async_trait]
+impl RatingStore for MemoryRatings {
+    async fn get(&self, name: &str) -> Rating {
+        self.ratings
+            .lock()
+            .await
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+    async fn update(&self, names: &[&str], update: RatingUpdate<'_>) -> Vec<Rating> {
+        update_map(&mut *self.ratings.lock().await, names, update)
+    }
+}
+
+/// Keeps ratings in a JSON file, which is rewritten whenever a rating changes.
+pub struct FileRatings {
+    path: PathBuf,
+    ratings: Mutex<HashMap<String, Rating>>,
+}
+
+impl FileRatings {
+    /// Loads the ratings from `path`, or starts with no ratings if the file doesn't exist yet.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let ratings = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            ratings: Mutex::new(ratings),
+        })
+    }
+}
+
+#[async_trait]
+impl RatingStore for FileRatings {
+    async fn get(&self, name: &str) -> Rating {
+        self.ratings
+            .lock()
+            .await
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+    async fn update(&self, names: &[&str], update: RatingUpdate<'_>) -> Vec<Rating> {
+        // the lock is held until the file has been written, so that a newer version can't be overwritten by an older one
+        let mut ratings = self.ratings.lock().await;
+        let new = update_map(&mut ratings, names, update);
+        let Ok(data) = serde_json::to_vec(&*ratings) else {
+            return new;
+        };
+        // write to a temporary file first so that the ratings aren't lost if the server stops while writing
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        if tokio::fs::write(&tmp, data).await.is_ok() {
+            _ = tokio::fs::rename(&tmp, &self.path).await;
+        }
+        new
+    }
+}
+
+/// Settings for ranked matchmaking, see `ServerBuilder::ranked_matchmaking`.
+#[derive(Clone, Debug)]
+pub struct RankedMatchmaking {
+    /// How many players are put into a lobby together. Defaults to 2.
+    pub players: usize,
+    /// At first, players are only matched with players whose rating is at most this far from theirs.
+    /// Defaults to 100.
+    pub window: f64,
+    /// How much the window grows per second of waiting. Defaults to 10.
+    pub widen_per_second: f64,
+    /// The window never grows beyond this. Defaults to no limit.
+    pub max_window: Option<f64>,
+}
+
+impl RankedMatchmaking {
+    /// The rating difference a player who waited for `waited` accepts.
+    pub fn window(&self, waited: Duration) -> f64 {
+        let window = self.window + self.widen_per_second * waited.as_secs_f64();
+        match self.max_window {
+            Some(max) => window.min(max),
+            None => window,
+        }
+    }
+}
+
+impl Default for RankedMatchmaking {
+    fn default() -> Self {
+        Self {
+            players: 2,
+            window: 100.0,
+            widen_per_second: 10.0,
+            max_window: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(value: f64, games: u32) -> Rating {
+        Rating { value, games }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn winner_gains_what_loser_loses() {
+        let new = elo(&[(rating(1600.0, 20), 0), (rating(1400.0, 20), 1)]);
+        assert!(new[0].value > 1600.0);
+        assert_close(new[0].value - 1600.0, 1400.0 - new[1].value);
+        assert_eq!((new[0].games, new[1].games), (21, 21));
+    }
+
+    #[test]
+    fn new_players_change_faster() {
+        let new = elo(&[(rating(1500.0, 0), 0), (rating(1500.0, 0), 1)]);
+        assert_close(new[0].value, 1520.0);
+        assert_close(new[1].value, 1480.0);
+        let new = elo(&[(rating(1500.0, 10), 0), (rating(1500.0, 10), 1)]);
+        assert_close(new[0].value, 1510.0);
+        assert_close(new[1].value, 1490.0);
+    }
+
+    #[test]
+    fn draw_between_equals_changes_nothing() {
+        let new = elo(&[(rating(1500.0, 3), 0), (rating(1500.0, 3), 0)]);
+        assert_close(new[0].value, 1500.0);
+        assert_close(new[1].value, 1500.0);
+        assert_eq!(new[0].games, 4);
+    }
+
+    #[test]
+    fn draw_moves_ratings_together() {
+        let new = elo(&[(rating(1700.0, 20), 0), (rating(1300.0, 20), 0)]);
+        assert!(new[0].value < 1700.0);
+        assert!(new[1].value > 1300.0);
+    }
+
+    #[test]
+    fn single_player_keeps_their_rating() {
+        let new = elo(&[(rating(1550.0, 5), 0)]);
+        assert_close(new[0].value, 1550.0);
+        assert_eq!(new[0].games, 6);
+        assert!(elo(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn update_stores_new_ratings() {
+        let store = MemoryRatings::default();
+        let new = store
+            .update(&["Mark", "Anna"], &|old| {
+                assert_eq!(old, [Rating::INITIAL, Rating::INITIAL]);
+                vec![rating(1520.0, 1), rating(1480.0, 1)]
+            })
+            .await;
+        assert_eq!(new, [rating(1520.0, 1), rating(1480.0, 1)]);
+        assert_eq!(store.get("Mark").await, rating(1520.0, 1));
+        assert_eq!(store.get("Anna").await, rating(1480.0, 1));
+        assert_eq!(store.get("Tom").await, Rating::INITIAL);
+    }
+
+    #[tokio::test]
+    async fn update_refuses_duplicate_names() {
+        let store = MemoryRatings::default();
+        let new = store
+            .update(&["Mark", "Mark"], &|_| {
+                panic!("one player can't have two places");
+            })
+            .await;
+        assert_eq!(new, [Rating::INITIAL, Rating::INITIAL]);
+        assert_eq!(store.get("Mark").await, Rating::INITIAL);
+        assert!(!has_duplicates(&["Mark", "Anna"]));
+    }
+
+    #[test]
+    fn matches_count_as_one_game() {
+        // against two equal opponents, winning both counts like winning one 1v1
+        let new = elo(&[
+            (rating(1500.0, 0), 0),
+            (rating(1500.0, 0), 1),
+            (rating(1500.0, 0), 2),
+        ]);
+        assert_close(new[0].value, 1520.0);
+        assert_close(new[1].value, 1500.0);
+        assert_close(new[2].value, 1480.0);
+    }
+}
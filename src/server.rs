@@ -0,0 +1,790 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use rand::seq::SliceRandom;
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, watch, Mutex, Notify},
+};
+use tokio_tungstenite::tungstenite::Message;
+
+#[cfg(feature = "tls")]
+use crate::tls::{self, Tls};
+use crate::{
+    connection::{Connection, WebSocket},
+    game::{
+        shutdown_close_frame, Lobby, LobbyId, LobbyListing, LobbyState, PlayerCon, PublicLobby,
+    },
+    handshake::{Handshake, JoinError, JoinRequest, LobbyList},
+    http,
+    lobby_task::{Join, LobbyTask},
+    matchmaking::{self, Matchmaking},
+    rating::{MemoryRatings, RankedMatchmaking, RatingStore},
+    site::Files,
+    transport::{Listener, Transport},
+};
+
+/// A bound game server.
+///
+/// Create one using `Server::<YourLobbyState>::builder()`,
+/// configure it, then `bind` it to an address and `run` it.
+pub struct Server<S: LobbyState> {
+    /// `None` if connections only come from `Connector`s
+    listener: Option<Box<dyn Listener>>,
+    connector: Connector,
+    /// connections from `Connector`s
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    config: Arc<Config>,
+    _state: PhantomData<fn() -> S>,
+}
+
+/// Hands connections which were accepted somewhere else to a `Server`, see `Server::connector`.
+#[derive(Clone)]
+pub struct Connector {
+    sender: mpsc::UnboundedSender<Incoming>,
+}
+
+/// a new connection
+enum Incoming {
+    /// still needs the TLS (if enabled) and WebSocket handshakes
+    Transport(Box<dyn Transport>),
+    /// already upgraded to a WebSocket, for example by axum
+    #[cfg(feature = "axum")]
+    WebSocket(Box<WebSocket>),
+}
+
+impl Connector {
+    /// Handles `con` like a connection from the server's listener:
+    /// after the TLS handshake (if enabled) and the WebSocket handshake,
+    /// the client is sent to a lobby.
+    /// If the server has already shut down, `con` is dropped.
+    pub fn connect(&self, con: impl Transport) {
+        _ = self.sender.send(Incoming::Transport(Box::new(con)));
+    }
+    /// Like `connect`, for connections which already are WebSockets.
+    /// TLS is up to whatever accepted them.
+    #[cfg(feature = "axum")]
+    pub(crate) fn connect_websocket(&self, con: WebSocket) {
+        _ = self.sender.send(Incoming::WebSocket(Box::new(con)));
+    }
+}
+
+/// Configures a `Server`. See `Server::builder()`.
+pub struct ServerBuilder<S: LobbyState> {
+    config: Config,
+    /// certificate and key, loaded in `bind`
+    #[cfg(feature = "tls")]
+    tls: Option<(std::path::PathBuf, std::path::PathBuf)>,
+    _state: PhantomData<fn() -> S>,
+}
+
+pub(crate) struct Config {
+    pub(crate) lobby_tick: Duration,
+    pub(crate) game_tick: Duration,
+    pub(crate) max_lobbies: Option<usize>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) ping_interval: Option<Duration>,
+    pub(crate) ping_timeout: Option<Duration>,
+    pub(crate) reconnect_grace: Option<Duration>,
+    pub(crate) lobby_code_length: usize,
+    pub(crate) lobby_code_alphabet: Vec<u8>,
+    pub(crate) lobby_code_reuse_delay: Duration,
+    pub(crate) ratings: Arc<dyn RatingStore>,
+    pub(crate) ranked: Option<RankedMatchmaking>,
+    pub(crate) outbound_queue: usize,
+    pub(crate) overflow: Overflow,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<Tls>>,
+    pub(crate) site: Option<Arc<dyn Files>>,
+}
+
+/// What happens when a message is sent to a player whose outbound queue is full,
+/// see `ServerBuilder::outbound_queue`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// The oldest queued message is dropped.
+    DropOldest,
+    /// If the message was sent using `send_keyed`, it replaces the queued message with the same key.
+    /// Otherwise, or if there is no such message, the player is disconnected.
+    Coalesce,
+    /// The player is disconnected.
+    Disconnect,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            lobby_tick: Duration::from_millis(100),
+            game_tick: Duration::from_millis(10),
+            max_lobbies: None,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            ping_interval: Some(Duration::from_secs(10)),
+            ping_timeout: Some(Duration::from_secs(30)),
+            reconnect_grace: None,
+            lobby_code_length: 4,
+            // no 0/O, 1/I/L, which are easy to mix up
+            lobby_code_alphabet: b"ABCDEFGHJKMNPQRSTUVWXYZ23456789".to_vec(),
+            lobby_code_reuse_delay: Duration::from_secs(600),
+            ratings: Arc::new(MemoryRatings::default()),
+            ranked: None,
+            outbound_queue: 1024,
+            overflow: Overflow::Disconnect,
+            #[cfg(feature = "tls")]
+            tls: None,
+            site: None,
+        }
+    }
+}
+
+impl Config {
+    /// The lobby code a player typed in, if it could be one.
+    /// Codes are case-insensitive unless the alphabet contains lowercase letters.
+    pub(crate) fn parse_lobby_code(&self, code: &str) -> Option<LobbyId> {
+        let code = code.trim();
+        if self.lobby_code_alphabet.iter().any(u8::is_ascii_lowercase) {
+            LobbyId::new(code)
+        } else {
+            LobbyId::new(&code.to_ascii_uppercase())
+        }
+    }
+}
+
+impl<S: LobbyState + 'static> Server<S> {
+    pub fn builder() -> ServerBuilder<S> {
+        ServerBuilder {
+            config: Config::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            _state: PhantomData,
+        }
+    }
+    /// The address the server is listening on.
+    /// Useful if you bound to port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the server has no listener",
+            )),
+        }
+    }
+    /// Lets connections which weren't accepted by the server's listener join,
+    /// for example one half of a `tokio::io::duplex` in tests.
+    pub fn connector(&self) -> Connector {
+        self.connector.clone()
+    }
+    /// Runs the server forever.
+    pub async fn run(self) {
+        self.run_until(std::future::pending()).await
+    }
+    /// Runs the server until `shutdown` completes.
+    ///
+    /// On shutdown, no new connections are accepted,
+    /// `GameState::server_shutdown` is called for every running game and
+    /// `LobbyState::server_shutdown` for every lobby,
+    /// and all players are disconnected with a close frame.
+    /// Returns once everything has been shut down.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        let (stop, stopped) = watch::channel(false);
+        // every task holds a reference to `shared`, which holds `running`,
+        // so `lobbies_done.recv()` returns `None` once all of them have finished.
+        let (running, mut lobbies_done) = mpsc::channel::<()>(1);
+        let (queue, ranked_queue) = mpsc::unbounded_channel();
+        let ranked = self.config.ranked.clone();
+        let shared = Arc::new(Shared::<S> {
+            registry: Default::default(),
+            matchmaking: ranked.is_some().then(|| Matchmaking::new(queue)),
+            config: self.config,
+            stopped,
+            _running: running,
+        });
+        if let (Some(settings), Some(matchmaking)) = (ranked, &shared.matchmaking) {
+            tokio::spawn(matchmaking::run(
+                settings,
+                ranked_queue,
+                Arc::clone(&matchmaking.events),
+                Arc::clone(&shared),
+            ));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &shared.config.tls {
+            tokio::spawn(tls::reload_on_change(
+                Arc::clone(tls),
+                shared.stopped.clone(),
+            ));
+        }
+        let accept = tokio::spawn(accept_new(
+            self.listener,
+            self.incoming,
+            Arc::clone(&shared),
+        ));
+        shutdown.await;
+        _ = stop.send(true);
+        _ = accept.await;
+        drop(shared);
+        _ = lobbies_done.recv().await;
+    }
+}
+
+impl<S: LobbyState + 'static> ServerBuilder<S> {
+    /// How long to wait between two calls to `LobbyState::lobby_update`.
+    /// Defaults to 100ms.
+    pub fn lobby_tick(mut self, interval: Duration) -> Self {
+        self.config.lobby_tick = interval;
+        self
+    }
+    /// How long to wait between two calls to `GameState::update`.
+    /// Defaults to 10ms.
+    pub fn game_tick(mut self, interval: Duration) -> Self {
+        self.config.game_tick = interval;
+        self
+    }
+    /// The maximum number of lobbies (including those in a game).
+    /// Once this is reached, attempts to create a new lobby are rejected with `JoinError::TooManyLobbies`.
+    /// Unlimited by default.
+    pub fn max_lobbies(mut self, max: usize) -> Self {
+        self.config.max_lobbies = Some(max);
+        self
+    }
+    /// How long a new connection may take to say which lobby it wants to join
+    /// before it is dropped. Defaults to 10s, `None` waits forever.
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+    /// How often to ping every player to check if they are still connected.
+    /// Defaults to 10s, `None` disables pings.
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.ping_interval = interval;
+        self
+    }
+    /// If a player doesn't send anything (including responses to pings) for this long,
+    /// they are considered disconnected, and `player_leaving` is called.
+    /// This should be longer than `ping_interval`.
+    /// Defaults to 30s, `None` waits forever.
+    pub fn ping_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.ping_timeout = timeout;
+        self
+    }
+    /// Allows players to reconnect after losing their connection.
+    ///
+    /// A disconnected player keeps their place in the lobby for `grace`,
+    /// and `player_disconnected` is called instead of `player_leaving`.
+    /// If a new connection resumes the player's session in its handshake (see the `handshake` module)
+    /// using the player's `session_token()`, it takes over their place,
+    /// and `player_reconnected` is called. After `grace`, `player_leaving` is called as usual.
+    ///
+    /// This also works while the player's old connection still looks alive,
+    /// for example when a phone switched networks before `ping_timeout` ran out.
+    /// The old connection is then closed with code 4006.
+    ///
+    /// Disabled (`None`) by default.
+    pub fn reconnect_grace(mut self, grace: Option<Duration>) -> Self {
+        self.config.reconnect_grace = grace;
+        self
+    }
+    /// How many characters a lobby code has. Defaults to 4.
+    ///
+    /// Panics if `length` is 0 or more than `LobbyId::MAX_LEN`.
+    pub fn lobby_code_length(mut self, length: usize) -> Self {
+        assert!(
+            (1..=LobbyId::MAX_LEN).contains(&length),
+            "lobby codes must have between 1 and {} characters",
+            LobbyId::MAX_LEN
+        );
+        self.config.lobby_code_length = length;
+        self
+    }
+    /// The characters lobby codes are made of.
+    /// Defaults to uppercase letters and digits, except for `0`, `O`, `1`, `I` and `L`.
+    /// If there are no lowercase letters in `alphabet`, codes are case-insensitive.
+    ///
+    /// Panics if `alphabet` is empty or not ascii.
+    pub fn lobby_code_alphabet(mut self, alphabet: &str) -> Self {
+        assert!(
+            !alphabet.is_empty() && alphabet.is_ascii(),
+            "the lobby code alphabet must be non-empty ascii"
+        );
+        let mut alphabet = alphabet.as_bytes().to_vec();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        self.config.lobby_code_alphabet = alphabet;
+        self
+    }
+    /// After a lobby closes, its code isn't given to a new lobby for this long,
+    /// so that old invite links don't lead to a stranger's lobby.
+    /// Defaults to 10 minutes.
+    pub fn lobby_code_reuse_delay(mut self, delay: Duration) -> Self {
+        self.config.lobby_code_reuse_delay = delay;
+        self
+    }
+    /// Messages sent to a player are put into a queue, which a background task sends,
+    /// so that a player with a slow connection doesn't slow down the game.
+    /// Once `capacity` messages are waiting, `overflow` decides what happens.
+    /// Defaults to 1024 and `Overflow::Disconnect`.
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn outbound_queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        assert!(
+            capacity > 0,
+            "the outbound queue must have room for a message"
+        );
+        self.config.outbound_queue = capacity;
+        self.config.overflow = overflow;
+        self
+    }
+    /// Where player ratings are kept, see the `rating` module.
+    /// Defaults to `MemoryRatings`, so ratings are lost when the server stops.
+    pub fn ratings(mut self, ratings: impl RatingStore) -> Self {
+        self.config.ratings = Arc::new(ratings);
+        self
+    }
+    /// Lets clients wait for players with a similar rating using a `ranked` handshake (see the `rating` module).
+    /// Disabled by default.
+    ///
+    /// Panics if `settings.players` is 0.
+    pub fn ranked_matchmaking(mut self, settings: RankedMatchmaking) -> Self {
+        assert!(
+            settings.players > 0,
+            "ranked matchmaking needs at least one player per lobby"
+        );
+        self.config.ranked = Some(settings);
+        self
+    }
+    /// Accepts only wss:// connections, using the PEM encoded certificate chain in `cert`
+    /// and the private key in `key`. The files are loaded in `bind`.
+    ///
+    /// While the server is running, they are loaded again when they change
+    /// (checked every 10 seconds) or when the process receives SIGHUP,
+    /// so renewed certificates are used without restarting the server.
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        mut self,
+        cert: impl Into<std::path::PathBuf>,
+        key: impl Into<std::path::PathBuf>,
+    ) -> Self {
+        self.tls = Some((cert.into(), key.into()));
+        self
+    }
+    /// Answers requests which aren't WebSocket handshakes with `files`,
+    /// so that the server also serves the game's website, see the `site` module.
+    /// Connections from `axum` don't use this, since axum serves the site itself.
+    /// Calling this again replaces the previous `files`.
+    pub fn serve(mut self, files: impl Files) -> Self {
+        self.config.site = Some(Arc::new(files));
+        self
+    }
+    /// Binds the server to `addr`. Use `Server::run` to start it.
+    ///
+    /// With `tls`, this fails if the certificate or key can't be loaded.
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> std::io::Result<Server<S>> {
+        let listener = TcpListener::bind(addr).await?;
+        self.listen(listener).await
+    }
+    /// Like `bind`, but accepts connections from `listener`, for example a `UnixListener`.
+    pub async fn listen(self, listener: impl Listener) -> std::io::Result<Server<S>> {
+        self.into_server(Some(Box::new(listener))).await
+    }
+    /// Creates a server without a listener.
+    /// Connections can only be passed to it using `Server::connector`.
+    pub async fn build(self) -> std::io::Result<Server<S>> {
+        self.into_server(None).await
+    }
+    async fn into_server(self, listener: Option<Box<dyn Listener>>) -> std::io::Result<Server<S>> {
+        #[cfg(feature = "tls")]
+        let config = Config {
+            tls: match self.tls {
+                Some((cert, key)) => Some(Arc::new(Tls::load(cert, key).await?)),
+                None => None,
+            },
+            ..self.config
+        };
+        #[cfg(not(feature = "tls"))]
+        let config = self.config;
+        let (sender, incoming) = mpsc::unbounded_channel();
+        Ok(Server {
+            listener,
+            connector: Connector { sender },
+            incoming,
+            config: Arc::new(config),
+            _state: PhantomData,
+        })
+    }
+}
+
+pub(crate) struct Shared<S: LobbyState> {
+    pub(crate) registry: Mutex<Registry<S>>,
+    /// `None` if ranked matchmaking is disabled
+    pub(crate) matchmaking: Option<Matchmaking<S>>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) stopped: watch::Receiver<bool>,
+    _running: mpsc::Sender<()>,
+}
+
+pub(crate) struct Registry<S: LobbyState> {
+    /// A lobby is removed when its task ends.
+    pub(crate) lobbies: HashMap<LobbyId, LobbyHandle<S>>,
+    /// codes of recently closed lobbies, and when they were closed.
+    /// These aren't reused until `lobby_code_reuse_delay` has passed.
+    retired: HashMap<LobbyId, Instant>,
+    /// session tokens of the players who can reconnect, and the lobby they are in.
+    /// Only used if reconnecting is enabled.
+    pub(crate) sessions: HashMap<String, LobbyId>,
+}
+
+impl<S: LobbyState> Default for Registry<S> {
+    fn default() -> Self {
+        Self {
+            lobbies: HashMap::new(),
+            retired: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl<S: LobbyState> Registry<S> {
+    /// A random code which isn't used by any lobby and wasn't used recently,
+    /// or `None` if (almost) all codes are taken.
+    fn new_lobby_id(&mut self, config: &Config) -> Option<LobbyId> {
+        self.retired
+            .retain(|_, closed| closed.elapsed() < config.lobby_code_reuse_delay);
+        let possible_codes = config
+            .lobby_code_alphabet
+            .len()
+            .checked_pow(config.lobby_code_length as u32)
+            .unwrap_or(usize::MAX);
+        if self.lobbies.len() + self.retired.len() >= possible_codes {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        // if most codes are taken, this could take a while, so give up eventually
+        for _ in 0..100 {
+            let code = (0..config.lobby_code_length)
+                .map(|_| *config.lobby_code_alphabet.choose(&mut rng).unwrap() as char)
+                .collect::<String>();
+            let id = LobbyId::new(&code)?;
+            if !self.lobbies.contains_key(&id) && !self.retired.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+    /// Removes a lobby whose task has ended.
+    pub(crate) fn remove_lobby(&mut self, id: LobbyId) {
+        self.lobbies.remove(&id);
+        self.sessions.retain(|_, lobby| *lobby != id);
+        self.retired.insert(id, Instant::now());
+    }
+}
+
+/// Used to join a lobby whose task is running.
+pub(crate) struct LobbyHandle<S: LobbyState> {
+    joins: mpsc::UnboundedSender<Join<S::PlayerState>>,
+    /// passed to the players' reader tasks
+    events: Arc<Notify>,
+    /// kept up to date by the lobby's task, `None` if the lobby isn't public
+    listing: watch::Receiver<Option<PublicLobby>>,
+    /// players sent to `joins` who aren't counted in `listing` yet
+    joining: Arc<AtomicUsize>,
+}
+
+impl<S: LobbyState> LobbyHandle<S> {
+    fn join(&self, con: WebSocket, request: JoinRequest, config: &Config) {
+        self.joining.fetch_add(1, Ordering::Relaxed);
+        _ = self.joins.send(Join::New(Box::new(PlayerCon::new(
+            S::new_player(),
+            request,
+            Connection::new(con, Arc::clone(&self.events), config),
+        ))));
+    }
+    /// Like `join`, for a player whose connection was already notifying someone else.
+    pub(crate) fn join_player(&self, player: PlayerCon<S::PlayerState>) {
+        player.set_events(Arc::clone(&self.events));
+        self.joining.fetch_add(1, Ordering::Relaxed);
+        _ = self.joins.send(Join::New(Box::new(player)));
+        // for messages the player sent before
+        self.events.notify_one();
+    }
+    /// This lobby's public entry as quickplay should see it, if quickplayers can join it.
+    fn quickplay_candidate(&self) -> Option<PublicLobby> {
+        // read `joining` first: the lobby's task updates `listing` before it decreases `joining`,
+        // so players are counted twice rather than not at all.
+        let joining = self.joining.load(Ordering::Acquire);
+        let mut lobby = self.listing.borrow().clone()?;
+        lobby.players += joining;
+        (!lobby.in_game && !lobby.is_full()).then_some(lobby)
+    }
+}
+
+impl<S: LobbyState + 'static> Registry<S> {
+    /// Starts a new lobby for a player who wants to create one, who should join it right away.
+    /// `None` if there are too many lobbies.
+    pub(crate) fn create_lobby(
+        &mut self,
+        shared: &Arc<Shared<S>>,
+        request: &JoinRequest,
+    ) -> Option<&LobbyHandle<S>> {
+        if shared
+            .config
+            .max_lobbies
+            .is_some_and(|max| self.lobbies.len() >= max)
+        {
+            return None;
+        }
+        let id = self.new_lobby_id(&shared.config)?;
+        let lobby = Lobby::new(S::new(), Arc::clone(&shared.config.ratings));
+        // `LobbyState::listing` is only called once the lobby's task has handled the first player.
+        // So that other quickplayers can join a lobby created by quickplay in the meantime, it starts out public.
+        let listing = request.quickplay.then(|| PublicLobby {
+            code: id,
+            listing: LobbyListing {
+                mode: request.mode.clone().unwrap_or_default(),
+                ..Default::default()
+            },
+            players: 0,
+            max_players: lobby.max_players,
+            spectators: 0,
+            in_game: false,
+        });
+        let (joins_sender, joins) = mpsc::unbounded_channel();
+        let (listing_sender, listing) = watch::channel(listing);
+        let joining = Arc::new(AtomicUsize::new(0));
+        let handle = LobbyHandle {
+            joins: joins_sender,
+            events: Arc::clone(&lobby.events),
+            listing,
+            joining: Arc::clone(&joining),
+        };
+        // the task can't close the lobby before the first player joined,
+        // because it has to lock the registry to do that.
+        tokio::spawn(
+            LobbyTask::new(
+                id,
+                lobby,
+                joins,
+                listing_sender,
+                joining,
+                Arc::clone(shared),
+            )
+            .run(),
+        );
+        Some(self.lobbies.entry(id).or_insert(handle))
+    }
+}
+
+async fn accept_new<S: LobbyState + 'static>(
+    mut listener: Option<Box<dyn Listener>>,
+    mut incoming: mpsc::UnboundedReceiver<Incoming>,
+    shared: Arc<Shared<S>>,
+) {
+    let mut stopped = shared.stopped.clone();
+    loop {
+        let con = tokio::select! {
+            _ = wait_for_shutdown(&mut stopped) => return,
+            con = async { listener.as_mut().unwrap().accept().await }, if listener.is_some() => {
+                let Ok(con) = con else {
+                    continue;
+                };
+                Incoming::Transport(con)
+            }
+            Some(con) = incoming.recv() => con,
+        };
+        tokio::spawn(handle_new_connection(con, Arc::clone(&shared)));
+    }
+}
+
+pub(crate) async fn wait_for_shutdown(stopped: &mut watch::Receiver<bool>) {
+    _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+/// `None` if `deadline` passes before `future` completes
+async fn before<F: Future>(deadline: Option<tokio::time::Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// like `tokio::time::interval`, but if a tick takes too long,
+/// the next one is delayed instead of trying to catch up.
+pub(crate) fn interval(period: Duration) -> tokio::time::Interval {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval
+}
+
+/// the TLS handshake (if enabled), then the WebSocket handshake
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn accept_websocket(con: Box<dyn Transport>, config: &Config) -> Option<WebSocket> {
+    #[cfg(feature = "tls")]
+    let con: Box<dyn Transport> = match &config.tls {
+        Some(tls) => Box::new(tls.acceptor().accept(con).await.ok()?),
+        None => con,
+    };
+    match &config.site {
+        Some(files) => http::accept(con, files.as_ref(), config).await,
+        None => tokio_tungstenite::accept_async(con).await.ok(),
+    }
+}
+
+async fn handle_new_connection<S: LobbyState + 'static>(con: Incoming, shared: Arc<Shared<S>>) {
+    let deadline = shared
+        .config
+        .handshake_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut stopped = shared.stopped.clone();
+    let con = match con {
+        Incoming::Transport(con) => tokio::select! {
+            _ = wait_for_shutdown(&mut stopped) => return,
+            con = before(deadline, accept_websocket(con, &shared.config)) => con.flatten(),
+        },
+        #[cfg(feature = "axum")]
+        Incoming::WebSocket(con) => Some(*con),
+    };
+    let Some(mut con) = con else {
+        return;
+    };
+    let msg = tokio::select! {
+        _ = wait_for_shutdown(&mut stopped) => {
+            _ = con.close(Some(shutdown_close_frame())).await;
+            return;
+        }
+        msg = before(deadline, con.next()) => msg,
+    };
+    let handshake = match msg {
+        Some(Some(Ok(Message::Text(msg)))) => Handshake::parse(&msg, &shared.config),
+        // closed before sending anything
+        Some(None | Some(Err(_) | Ok(Message::Close(_)))) => return,
+        // timed out, or not a text message
+        None | Some(Some(Ok(_))) => Err(JoinError::BadHandshake),
+    };
+    let handshake = match handshake {
+        Ok(handshake) => handshake,
+        Err(error) => {
+            _ = con.close(Some(error.close_frame())).await;
+            return;
+        }
+    };
+    let mut registry = shared.registry.lock().await;
+    if *shared.stopped.borrow() {
+        drop(registry);
+        _ = con.close(Some(shutdown_close_frame())).await;
+        return;
+    }
+    match handshake {
+        Handshake::New(request) => {
+            if let Some(l) = registry.create_lobby(&shared, &request) {
+                l.join(con, request, &shared.config);
+            } else {
+                drop(registry);
+                _ = con
+                    .close(Some(JoinError::TooManyLobbies.close_frame()))
+                    .await;
+            }
+        }
+        Handshake::Join(id, request) => {
+            if let Some(l) = registry.lobbies.get(&id) {
+                l.join(con, request, &shared.config);
+            } else {
+                drop(registry);
+                _ = con
+                    .close(Some(JoinError::LobbyNotFound.close_frame()))
+                    .await;
+            }
+        }
+        Handshake::Quickplay(request) => {
+            // the registry stays locked until the player was sent to a lobby, so the next quickplayer
+            // already sees them in it, or sees the lobby they created.
+            let best = registry
+                .lobbies
+                .values()
+                .filter_map(|l| {
+                    let lobby = l.quickplay_candidate()?;
+                    Some((S::quickplay_score(&lobby, &request)?, l))
+                })
+                .max_by_key(|(score, _)| *score);
+            if let Some((_, l)) = best {
+                l.join(con, request, &shared.config);
+            } else if let Some(l) = registry.create_lobby(&shared, &request) {
+                l.join(con, request, &shared.config);
+            } else {
+                drop(registry);
+                _ = con
+                    .close(Some(JoinError::TooManyLobbies.close_frame()))
+                    .await;
+            }
+        }
+        Handshake::Ranked(request) => {
+            drop(registry);
+            let Some(matchmaking) = &shared.matchmaking else {
+                let error = JoinError::Rejected("ranked matchmaking is disabled".to_owned());
+                _ = con.close(Some(error.close_frame())).await;
+                return;
+            };
+            if request.name.is_empty() {
+                let error = JoinError::Rejected("ranked matchmaking needs a name".to_owned());
+                _ = con.close(Some(error.close_frame())).await;
+                return;
+            }
+            // ratings belong to names, so one name can't be in two ranked games at once
+            let Some(name) = matchmaking.claim(&request.name) else {
+                let error = JoinError::Rejected("this name is already playing ranked".to_owned());
+                _ = con.close(Some(error.close_frame())).await;
+                return;
+            };
+            let rating = shared.config.ratings.get(&request.name).await;
+            let player = PlayerCon::new(
+                S::new_player(),
+                request,
+                Connection::new(con, Arc::clone(&matchmaking.events), &shared.config),
+            );
+            if let Err(mpsc::error::SendError((mut player, _, _))) =
+                matchmaking.queue.send((player, rating, name))
+            {
+                // the matchmaking task has stopped, so the server is shutting down
+                player.close_shutdown().await;
+            }
+        }
+        Handshake::List => {
+            let lobbies = registry
+                .lobbies
+                .values()
+                .filter_map(|l| l.listing.borrow().clone())
+                .collect();
+            drop(registry);
+            if let Ok(list) = serde_json::to_string(&LobbyList { lobbies }) {
+                _ = con.send(Message::Text(list)).await;
+            }
+            _ = con.close(None).await;
+        }
+        Handshake::Resume(token, welcome) => {
+            if let Some(l) = registry
+                .sessions
+                .get(&token)
+                .and_then(|id| registry.lobbies.get(id))
+            {
+                _ = l.joins.send(Join::Resume(
+                    token,
+                    welcome,
+                    Connection::new(con, Arc::clone(&l.events), &shared.config),
+                ));
+            } else {
+                drop(registry);
+                _ = con
+                    .close(Some(JoinError::SessionNotFound.close_frame()))
+                    .await;
+            }
+        }
+    }
+}
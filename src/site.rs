@@ -0,0 +1,76 @@
+//! Serving the game's website from the same port as its WebSocket, see `ServerBuilder::serve`.
+//!
+//! Requests which aren't WebSocket handshakes are answered with files from `Files`,
+//! for example a `Directory` or files which were embedded into the binary using `Embedded`.
+//!
+//! In HTML files, `\{websocket}` is replaced with the WebSocket's URL (like `wss://example.com/`),
+//! and `\{lobby}` with the lobby code from a `/join/<code>` link, or nothing.
+//! `/join/<code>` and other paths without a file extension which don't exist
+//! show `index.html`, so single-page apps can handle them.
+//! The JavaScript client (see `js`) is served at `/ezbrowsergameserver.js`.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+/// Where the files served by `ServerBuilder::serve` come from.
+#[async_trait]
+pub trait Files: Send + Sync + 'static {
+    /// The contents of the file at `path`, which is relative (like `index.html` or `img/logo.png`),
+    /// or `None` if there is no such file.
+    async fn get(&self, path: &str) -> Option<Cow<'static, [u8]>>;
+}
+
+/// Serves the files in a directory.
+/// They are read on every request, so changes show up without restarting the server.
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl Files for Directory {
+    async fn get(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        let path = Path::new(path);
+        // don't serve anything outside of `root`
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        let data = tokio::fs::read(self.root.join(path)).await.ok()?;
+        Some(Cow::Owned(data))
+    }
+}
+
+/// Serves files which are part of the binary, so the game is a single file:
+/// `Embedded::new().file("index.html", include_bytes!("site/index.html"))`
+#[derive(Default)]
+pub struct Embedded {
+    files: HashMap<&'static str, &'static [u8]>,
+}
+
+impl Embedded {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a file. `path` must not start with a `/`.
+    pub fn file(mut self, path: &'static str, data: &'static [u8]) -> Self {
+        self.files.insert(path, data);
+        self
+    }
+}
+
+#[async_trait]
+impl Files for Embedded {
+    async fn get(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        self.files.get(path).map(|data| Cow::Borrowed(*data))
+    }
+}
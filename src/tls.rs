@@ -0,0 +1,273 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::watch;
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    TlsAcceptor,
+};
+
+use crate::server::{interval, wait_for_shutdown};
+
+/// How often the certificate and key files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The certificate and key the server uses for wss://, see `ServerBuilder::tls`.
+pub(crate) struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Tls {
+    pub(crate) async fn load(cert: PathBuf, key: PathBuf) -> io::Result<Self> {
+        let config = server_config(&cert, &key).await?;
+        Ok(Self {
+            cert,
+            key,
+            acceptor: RwLock::new(TlsAcceptor::from(config)),
+        })
+    }
+    /// Used for new connections. Existing connections keep the certificate they started with.
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+    async fn reload(&self) -> io::Result<()> {
+        let config = server_config(&self.cert, &self.key).await?;
+        *self.acceptor.write().unwrap() = TlsAcceptor::from(config);
+        Ok(())
+    }
+    /// when the files were last changed
+    async fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path| async move { tokio::fs::metadata(path).await.ok()?.modified().ok() };
+        (modified(&self.cert).await, modified(&self.key).await)
+    }
+}
+
+/// Reloads the certificate and key when the files change or the process receives SIGHUP,
+/// until the server shuts down.
+///
+/// If they can't be loaded, for example because only one of them has been replaced so far,
+/// the old ones are kept and loading is tried again on the next check.
+pub(crate) async fn reload_on_change(tls: Arc<Tls>, stopped: watch::Receiver<bool>) {
+    reload_when_changed(tls, POLL_INTERVAL, stopped).await
+}
+
+/// `reload_on_change`, checking the files every `poll`
+async fn reload_when_changed(tls: Arc<Tls>, poll: Duration, mut stopped: watch::Receiver<bool>) {
+    let mut hangup = Hangup::new();
+    let mut poll = interval(poll);
+    let mut loaded = tls.modified().await;
+    loop {
+        let reload = tokio::select! {
+            _ = wait_for_shutdown(&mut stopped) => return,
+            _ = hangup.received() => true,
+            _ = poll.tick() => tls.modified().await != loaded,
+        };
+        if reload {
+            let modified = tls.modified().await;
+            if tls.reload().await.is_ok() {
+                loaded = modified;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+        Self(signal(SignalKind::hangup()).ok())
+    }
+    async fn received(&mut self) {
+        if let Some(signal) = &mut self.0 {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// there is no SIGHUP on this platform
+#[cfg(not(unix))]
+struct Hangup;
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Self
+    }
+    async fn received(&mut self) {
+        std::future::pending().await
+    }
+}
+
+async fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let cert_data = tokio::fs::read(cert).await?;
+    let certs = rustls_pemfile::certs(&mut cert_data.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", cert.display())));
+    }
+    let key_data = tokio::fs::read(key).await?;
+    let Some(key_der) = rustls_pemfile::private_key(&mut key_data.as_slice())? else {
+        return Err(invalid(format!("no private key in {}", key.display())));
+    };
+    // use ring explicitly, in case another crate also enables rustls' default provider
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+fn invalid(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use futures_util::{SinkExt, StreamExt};
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{CertificateDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+    use tokio_tungstenite::{client_async, tungstenite::Message};
+
+    use super::*;
+    use crate::prelude::*;
+
+    /// Sends every player the lobby code when they join.
+    struct TestLobby;
+
+    #[async_trait]
+    impl LobbyState for TestLobby {
+        type PlayerState = ();
+        type SpectatorState = ();
+        fn new() -> Self {
+            Self
+        }
+        fn new_player() {}
+        async fn player_joined(id: LobbyId, lobby: &mut Lobby<Self>, player: PlayerIndex) {
+            lobby.get_player(player).send(id.to_string()).await;
+        }
+        async fn lobby_update(
+            _id: LobbyId,
+            _lobby: &mut Lobby<Self>,
+        ) -> Option<Box<dyn GameState<Self>>> {
+            None
+        }
+        async fn player_leaving(_id: LobbyId, _lobby: &mut Lobby<Self>, _player: PlayerId) {}
+        async fn on_message(
+            _id: LobbyId,
+            _lobby: &mut Lobby<Self>,
+            _player: PlayerIndex,
+            _msg: String,
+        ) {
+        }
+    }
+
+    /// an empty directory for one test's files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ezbgs-{}-{name}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// writes a new self-signed certificate for localhost and its key to `dir`
+    fn write_cert(dir: &Path) -> CertificateDer<'static> {
+        let CertifiedKey { cert, key_pair } =
+            generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), key_pair.serialize_pem()).unwrap();
+        cert.der().clone()
+    }
+
+    /// a client which only trusts `cert`
+    fn trusting(cert: CertificateDer<'static>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    fn localhost() -> ServerName<'static> {
+        ServerName::try_from("localhost").unwrap()
+    }
+
+    #[tokio::test]
+    async fn wss_handshake() {
+        let dir = test_dir("wss");
+        let cert = write_cert(&dir);
+        let server = Server::<TestLobby>::builder()
+            .tls(dir.join("cert.pem"), dir.join("key.pem"))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = trusting(cert).connect(localhost(), tcp).await.unwrap();
+        let (mut client, _) = client_async("wss://localhost/", tls).await.unwrap();
+        client.send(Message::Text("new".to_owned())).await.unwrap();
+        let Some(Ok(Message::Text(code))) = client.next().await else {
+            panic!("expected the lobby code");
+        };
+        assert!(!code.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaced_files_are_reloaded() {
+        // true if a client which only trusts `cert` can connect
+        async fn accepts(tls: &Tls, cert: CertificateDer<'static>) -> bool {
+            let (client, server) = tokio::io::duplex(4096);
+            let acceptor = tls.acceptor();
+            tokio::spawn(async move { acceptor.accept(server).await });
+            trusting(cert).connect(localhost(), client).await.is_ok()
+        }
+        let dir = test_dir("reload");
+        let old = write_cert(&dir);
+        let tls = Arc::new(
+            Tls::load(dir.join("cert.pem"), dir.join("key.pem"))
+                .await
+                .unwrap(),
+        );
+        let (stop, stopped) = watch::channel(false);
+        let poll = Duration::from_millis(50);
+        tokio::spawn(reload_when_changed(Arc::clone(&tls), poll, stopped));
+        assert!(accepts(&tls, old.clone()).await);
+        // so that the files' modification times change
+        tokio::time::sleep(poll).await;
+        let new = write_cert(&dir);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !accepts(&tls, new.clone()).await {
+                tokio::time::sleep(poll).await;
+            }
+        })
+        .await
+        .expect("the new certificate wasn't loaded");
+        assert!(!accepts(&tls, old).await);
+        _ = stop.send(true);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+//! What the server's WebSockets run on.
+//!
+//! By default, the server accepts TCP connections (see `ServerBuilder::bind`),
+//! but any `Listener` can be used with `ServerBuilder::listen`, for example a `UnixListener`.
+//! Connections which were accepted somewhere else can be handed to the server using a `Connector`.
+
+use std::{io, net::SocketAddr};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// A connection to a client, which the WebSocket handshake and messages are sent over.
+///
+/// Implemented for everything that implements tokio's `AsyncRead` and `AsyncWrite`,
+/// like a `TcpStream`, a `UnixStream`, or one half of `tokio::io::duplex` in tests.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+/// Accepts new connections for a `Server`, see `ServerBuilder::listen`.
+#[async_trait]
+pub trait Listener: Send + 'static {
+    /// Waits for the next connection.
+    /// Errors are ignored, and `accept` is called again.
+    async fn accept(&mut self) -> io::Result<Box<dyn Transport>>;
+    /// The address the listener is listening on, see `Server::local_addr`.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this listener has no socket address",
+        ))
+    }
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> io::Result<Box<dyn Transport>> {
+        let (con, _) = TcpListener::accept(self).await?;
+        Ok(Box::new(con))
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for tokio::net::UnixListener {
+    async fn accept(&mut self) -> io::Result<Box<dyn Transport>> {
+        let (con, _) = tokio::net::UnixListener::accept(self).await?;
+        Ok(Box::new(con))
+    }
+}